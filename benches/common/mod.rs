@@ -0,0 +1,82 @@
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+use json_ecs_sub::{EcsSubApi, QuerySubReq, RegistryExt, ShortNameFilter};
+
+pub const ENTITY_COUNTS: [usize; 3] = [100, 1_000, 10_000];
+
+#[derive(Component, Reflect, serde::Serialize)]
+#[reflect(Serialize)]
+pub struct Location {
+    pub city: String,
+}
+
+#[derive(Component, Reflect, serde::Serialize)]
+#[reflect(Serialize)]
+pub struct Health {
+    pub health: u32,
+}
+
+/// Component of configurable size
+#[derive(Component, Reflect, serde::Serialize)]
+#[reflect(Serialize)]
+pub struct Payload {
+    pub values: Vec<f32>,
+}
+
+/// Every entity gets a `Location` and a `Payload` of `payload_len` floats, every other
+/// entity also gets a `Health`
+pub fn world_with(entities: usize, payload_len: usize) -> World {
+    let mut world = World::new();
+    populate(&mut world, entities, payload_len);
+    world
+}
+
+/// Same as `world_with` on an existing world, e.g. the one of an `App`
+pub fn populate(world: &mut World, entities: usize, payload_len: usize) {
+    world.register::<Location>();
+    world.register::<Health>();
+    world.register::<Payload>();
+    for i in 0..entities {
+        let mut entity = world.spawn((
+            Location {
+                city: format!("City {i}"),
+            },
+            Payload {
+                values: vec![i as f32; payload_len],
+            },
+        ));
+        if i % 2 == 0 {
+            entity.insert(Health { health: i as u32 });
+        }
+    }
+}
+
+pub fn subscribe(
+    api: &EcsSubApi,
+    world: &World,
+    id: &str,
+    fetch: &[&str],
+    filter: Vec<ShortNameFilter>,
+) {
+    api.subscribe_components(
+        QuerySubReq {
+            id: id.into(),
+            fetch: fetch
+                .iter()
+                .map(|short_name| short_name.to_string())
+                .collect(),
+            filter,
+            ..default()
+        },
+        world,
+    );
+}
+
+/// Changes the `Health` of every tenth entity on a new change tick
+pub fn damage_some(world: &mut World) {
+    world.increment_change_tick();
+    for mut health in world.query::<&mut Health>().iter_mut(world).step_by(10) {
+        health.health += 1;
+    }
+}
@@ -0,0 +1,89 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use json_ecs_sub::{EcsSubApi, QuerySubResp, ShortNameFilter};
+
+mod common;
+
+use common::ENTITY_COUNTS;
+
+const PAYLOAD_LENS: [usize; 3] = [0, 16, 256];
+
+fn filter_mixes() -> [(&'static str, Vec<ShortNameFilter>); 4] {
+    [
+        ("none", vec![]),
+        ("with", vec![ShortNameFilter::With("Health".into())]),
+        ("without", vec![ShortNameFilter::Without("Health".into())]),
+        ("changed", vec![ShortNameFilter::Changed("Health".into())]),
+    ]
+}
+
+fn subscribe_components(c: &mut Criterion) {
+    let mut group = c.benchmark_group("subscribe_components");
+    for entities in ENTITY_COUNTS {
+        let world = common::world_with(entities, 0);
+        group.bench_with_input(BenchmarkId::from_parameter(entities), &world, |b, world| {
+            b.iter_batched(
+                EcsSubApi::default,
+                |api| common::subscribe(&api, world, "Both", &["Location", "Health"], vec![]),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn run_all_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run_all_queries");
+    for entities in ENTITY_COUNTS {
+        for payload_len in PAYLOAD_LENS {
+            let mut world = common::world_with(entities, payload_len);
+            let api = EcsSubApi::default();
+            common::subscribe(&api, &world, "Locations", &["Location"], vec![]);
+            common::subscribe(&api, &world, "Payloads", &["Payload"], vec![]);
+            common::subscribe(
+                &api,
+                &world,
+                "Changed",
+                &["Health"],
+                vec![ShortNameFilter::Changed("Health".into())],
+            );
+            group.throughput(Throughput::Elements(entities as u64));
+            group.bench_function(format!("{entities}/payload_{payload_len}"), |b| {
+                b.iter(|| {
+                    common::damage_some(&mut world);
+                    api.run_all_queries(&world).len()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn run_query_internal(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run_query_internal");
+    for entities in ENTITY_COUNTS {
+        for (name, filter) in filter_mixes() {
+            let mut world = common::world_with(entities, 16);
+            let api = EcsSubApi::default();
+            common::subscribe(&api, &world, "Query", &["Location", "Payload"], filter);
+            group.throughput(Throughput::Elements(entities as u64));
+            group.bench_function(format!("{entities}/{name}"), |b| {
+                b.iter(|| {
+                    common::damage_some(&mut world);
+                    let mut queries = api.queries.write().unwrap();
+                    let sub = queries.get_mut(&(None, "Query".into())).unwrap();
+                    let resp: QuerySubResp = api.run_query_internal(&world, sub).unwrap();
+                    resp.matches.len()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    subscribe_components,
+    run_all_queries,
+    run_query_internal
+);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use json_ecs_sub::{protocol::ServerMsg, Compression, EcsSubApi};
+
+mod common;
+
+/// Per-component `RawValue`s plus a final `to_vec` against one reused buffer per subscription
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for entities in [1_000, 10_000] {
+        let world = common::world_with(entities, 0);
+        let api = EcsSubApi::default();
+        common::subscribe(&api, &world, "Both", &["Location", "Health"], vec![]);
+        let id = "Both".to_string();
+        group.bench_with_input(
+            BenchmarkId::new("raw_value", entities),
+            &world,
+            |b, world| {
+                b.iter(|| {
+                    serde_json::to_vec(&api.run_query(world, &id))
+                        .unwrap()
+                        .len()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("streamed", entities),
+            &world,
+            |b, world| {
+                b.iter(|| {
+                    let mut queries = api.queries.write().unwrap();
+                    api.write_query(world, queries.get_mut(&(None, id.clone())).unwrap())
+                        .unwrap()
+                        .len()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Every subscription written on the `ComputeTaskPool` and framed for the wire the way
+/// `query_runner` and the connection writers do, after a tenth of the `Health`s changed
+fn write_all_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_all_queries");
+    for entities in [1_000, 10_000] {
+        let mut world = common::world_with(entities, 0);
+        let api = EcsSubApi::default();
+        for i in 0..8 {
+            let id = format!("Both{i}");
+            common::subscribe(&api, &world, &id, &["Location", "Health"], vec![]);
+        }
+        group.bench_function(BenchmarkId::from_parameter(entities), |b| {
+            b.iter(|| {
+                common::damage_some(&mut world);
+                let mut len = 0;
+                api.write_all_queries(&world, |_, resp| {
+                    len += ServerMsg::QuerySubResp(resp)
+                        .to_frame(Compression::None)
+                        .as_bytes()
+                        .len();
+                });
+                len
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize, write_all_queries);
+criterion_main!(benches);
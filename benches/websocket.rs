@@ -0,0 +1,95 @@
+use std::{sync::mpsc, time::Duration};
+
+use bevy::prelude::*;
+use bevy_tokio_tasks::{TokioTasksPlugin, TokioTasksRuntime};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::{SinkExt, StreamExt};
+use json_ecs_sub::{
+    protocol::{ClientMsg, ServerMsg},
+    server::{self, Connections, ServerPlugin},
+    Compression, QuerySubReq,
+};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+mod common;
+
+use common::ENTITY_COUNTS;
+
+/// Runs a frame of a server app and times until a client on a local socket has received
+/// the subscription's response. Covers the whole path: `query_runner` serializing on the
+/// main thread with the work spread over the `ComputeTaskPool`, the dispatcher, the
+/// connection's outbound queue and its writer task.
+fn websocket_throughput(c: &mut Criterion) {
+    let client_rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("websocket");
+    for entities in ENTITY_COUNTS {
+        let mut app = App::new();
+        app.add_plugin(TokioTasksPlugin::default())
+            .add_plugins(MinimalPlugins)
+            .add_plugin(ServerPlugin::default());
+        common::populate(&mut app.world, entities, 16);
+        // Runs the startup systems
+        app.update();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = app.world.resource::<Connections>().clone();
+        app.world
+            .resource::<TokioTasksRuntime>()
+            .spawn_background_task(move |ctx| async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                server::network(ctx, connections, listener).await
+            });
+
+        let (msgs, received) = mpsc::channel();
+        let mut write = client_rt.block_on(async {
+            let (client, _) = connect_async(format!("ws://{addr}/socket")).await.unwrap();
+            let (write, mut read) = client.split();
+            tokio::spawn(async move {
+                while let Some(Ok(msg)) = read.next().await {
+                    if msgs.send(msg).is_err() {
+                        break;
+                    }
+                }
+            });
+            write
+        });
+        let subscribe = ClientMsg::Subscribe(QuerySubReq {
+            id: "Query".into(),
+            fetch: vec!["Location".into(), "Payload".into()],
+            ..default()
+        });
+        client_rt
+            .block_on(write.send(Message::text(serde_json::to_string(&subscribe).unwrap())))
+            .unwrap();
+
+        // Requests are handled on the main thread, which only runs during updates
+        loop {
+            app.update();
+            let Ok(msg) = received.recv_timeout(Duration::from_millis(10)) else {
+                continue;
+            };
+            let msg = ServerMsg::from_message(msg, Compression::None).unwrap();
+            if let ServerMsg::Ack { .. } = msg {
+                break;
+            }
+        }
+        // Drop the responses of frames run while waiting for the ack
+        while received.recv_timeout(Duration::from_millis(100)).is_ok() {}
+
+        group.throughput(Throughput::Elements(entities as u64));
+        group.bench_function(BenchmarkId::from_parameter(entities), |b| {
+            b.iter(|| {
+                app.update();
+                received.recv().unwrap().len()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, websocket_throughput);
+criterion_main!(benches);
@@ -1,50 +1,79 @@
 use std::error::Error;
 
 use futures_util::{SinkExt, StreamExt};
-use json_ecs_sub::QuerySubReq;
+use json_ecs_sub::{
+    compression::COMPRESSION_HEADER,
+    protocol::{ClientMsg, ServerMsg},
+    Compression, ConnectOptions, QuerySubReq,
+};
+use serde_json::value::RawValue;
 use tokio::sync::mpsc;
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 
-use crate::ServerMsg;
-
-pub fn client() -> Result<(), Box<dyn Error>> {
+pub fn client(options: ConnectOptions) -> Result<(), Box<dyn Error>> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?;
     rt.block_on(async move {
         let mut stdin = InteractiveStdin::new();
 
-        let (websocket, response) = connect_async("ws://localhost:3012/socket")
-            .await
-            .expect("Can't connect");
+        let url = format!("ws://localhost:3012/socket?{}", options.to_query());
+        let (websocket, response) = connect_async(url).await.expect("Can't connect");
         println!("Connection response: {response:?}");
+        // The server confirms the scheme it will actually use, older servers send nothing
+        let compression = response
+            .headers()
+            .get(COMPRESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Compression::from_name)
+            .unwrap_or_default();
         let (mut write, mut read) = websocket.split();
 
         tokio::spawn(async move {
             while let Some(msg) = read.next().await {
                 let msg = msg.unwrap();
-                let string = msg.into_text().unwrap();
-                let parsed: ServerMsg = serde_json::from_str(&string).unwrap();
-                match parsed {
-                    ServerMsg::Ack(x) => println!("Ack: {x:?}"),
-                    ServerMsg::QuerySubResp(x) => {
-                        println!("{}", serde_json::to_string(&x).unwrap())
-                    }
-                    ServerMsg::Text(x) => println!("Text: {x}"),
-                }
+                let parsed = ServerMsg::from_message(msg, compression).unwrap();
+                print_msg(parsed);
             }
         });
 
         loop {
-            println!("Enter query (e.g. Location Health) ");
+            println!(
+                "Enter query (e.g. Location Health), a change to it (e.g. modify Location), \
+                 events (e.g. events Damage), an event to send (e.g. send Damage {{\"amount\": 5}}) \
+                 or admin"
+            );
             let line = stdin.next_line().await.unwrap().unwrap();
-            let fetch = line.split(" ").map(|x| x.to_string()).collect();
-            let msg = serde_json::to_string(&QuerySubReq {
-                fetch,
-                filter: vec![],
-                id: "query_1".into(),
-            })
-            .unwrap();
+            let msg = if line.trim() == "admin" {
+                ClientMsg::Admin
+            } else if let Some(fetch) = line.strip_prefix("modify ") {
+                ClientMsg::Modify(QuerySubReq {
+                    fetch: fetch.split(" ").map(|x| x.to_string()).collect(),
+                    filter: vec![],
+                    id: "query_1".into(),
+                    ..Default::default()
+                })
+            } else if let Some(event) = line.strip_prefix("events ") {
+                ClientMsg::SubscribeEvents(event.trim().to_string())
+            } else if let Some(send) = line.strip_prefix("send ") {
+                let (event, payload) = send.split_once(' ').unwrap_or((send, "null"));
+                let Ok(payload) = RawValue::from_string(payload.to_string()) else {
+                    println!("Event payload must be json");
+                    continue;
+                };
+                ClientMsg::SendEvent {
+                    event: event.to_string(),
+                    payload,
+                }
+            } else {
+                ClientMsg::Subscribe(QuerySubReq {
+                    fetch: line.split(" ").map(|x| x.to_string()).collect(),
+                    filter: vec![],
+                    id: "query_1".into(),
+                    ..Default::default()
+                })
+            };
+            let msg = serde_json::to_string(&msg).unwrap();
             write.send(Message::text(msg)).await.unwrap();
             println!("Subscription message sent.");
         }
@@ -52,6 +81,30 @@ pub fn client() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn print_msg(msg: ServerMsg) {
+    match msg {
+        ServerMsg::Ack { req, .. } => println!("Ack: {req:?}"),
+        ServerMsg::QuerySubResp(x) => {
+            println!("{}", serde_json::to_string(&x).unwrap())
+        }
+        ServerMsg::EventSubResp(x) => {
+            println!("{}", serde_json::to_string(&x).unwrap())
+        }
+        ServerMsg::AggregateSubResp(x) => {
+            println!("{}", serde_json::to_string(&x).unwrap())
+        }
+        ServerMsg::Frame { stamp, msgs } => {
+            println!("Frame {}:", stamp.frame);
+            msgs.into_iter().for_each(print_msg);
+        }
+        ServerMsg::Admin { report, .. } => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap())
+        }
+        ServerMsg::Error { error, .. } => println!("Error: {error}"),
+        ServerMsg::Text { text, .. } => println!("Text: {text}"),
+    }
+}
+
 struct InteractiveStdin {
     chan: mpsc::Receiver<std::io::Result<String>>,
 }
@@ -0,0 +1,241 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use bevy::{prelude::*, utils::tracing::Instrument};
+use bevy_tokio_tasks::TaskContext;
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use json_ecs_sub::{
+    error::RequestError,
+    inspect,
+    protocol::ServerMsg,
+    server::{admin_report, with_api, Connections},
+    ConnectOptions, EcsSubApi, EntityId, QuerySubReq,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Local HTTP server next to the WebSocket one, for tools that only speak HTTP:
+///
+/// - `GET /metrics`: Prometheus metrics
+/// - `POST /query`: runs a `QuerySubReq` once and returns its `QuerySubResp`
+/// - `GET /components`: short names of the registered components
+/// - `GET /entities/{index}/{generation}`: every registered component of an entity
+/// - `GET /resources`: short names of the reflected resources
+/// - `GET /resources/{short_name}`: a resource reflected with `#[reflect(Resource)]`
+/// - `GET /sse?fetch=...` or `POST /sse`: subscribes to a query given in the uri, see
+///   `QuerySubReq::from_query`, or as a json body and streams its responses as
+///   server-sent events
+pub async fn serve(ctx: TaskContext, addr: SocketAddr, connections: Connections) -> crate::Result {
+    let make_service = make_service_fn(move |_| {
+        let (ctx, connections) = (ctx.clone(), connections.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, ctx.clone(), connections.clone())
+            }))
+        }
+    });
+    info!("HTTP listening on: {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+async fn handle(
+    req: Request<Body>,
+    mut ctx: TaskContext,
+    connections: Connections,
+) -> Result<Response<Body>, Infallible> {
+    // Owned so the body can still be taken out of `req`
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let resp = match (method, segments.as_slice()) {
+        (Method::GET, ["metrics"]) => {
+            let metrics = connections.metrics().clone();
+            let report = ctx
+                .run_on_main_thread(move |ctx| match ctx.world.get_resource::<EcsSubApi>() {
+                    Some(api) => admin_report(api, &connections),
+                    None => Default::default(),
+                })
+                .await;
+            Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics.render(&report)))
+                .unwrap()
+        }
+        (Method::POST, ["query"]) => {
+            let query: QuerySubReq = match read_json(req).await {
+                Ok(query) => query,
+                Err(resp) => return Ok(resp),
+            };
+            let resp = ctx
+                .run_on_main_thread(move |ctx| {
+                    let api = ctx.world.get_resource::<EcsSubApi>()?;
+                    Some(api.query_once(query, ctx.world))
+                })
+                .await;
+            match resp {
+                Some(Ok(resp)) => json(&resp),
+                Some(Err(e @ RequestError::Serialize(_))) => {
+                    error(StatusCode::INTERNAL_SERVER_ERROR, e)
+                }
+                Some(Err(e)) => error(StatusCode::BAD_REQUEST, e),
+                None => status(StatusCode::SERVICE_UNAVAILABLE),
+            }
+        }
+        (Method::GET, ["sse"]) => {
+            match QuerySubReq::from_query(req.uri().query().unwrap_or_default()) {
+                Ok(query) => stream_events(ctx, connections, query).await,
+                Err(e) => bad_request(e),
+            }
+        }
+        (Method::POST, ["sse"]) => match read_json(req).await {
+            Ok(query) => stream_events(ctx, connections, query).await,
+            Err(resp) => resp,
+        },
+        (Method::GET, ["components"]) => {
+            let names = ctx
+                .run_on_main_thread(|ctx| inspect::component_names(ctx.world))
+                .await;
+            json(&names)
+        }
+        (Method::GET, ["entities", index, generation]) => {
+            let (Ok(index), Ok(generation)) = (index.parse(), generation.parse()) else {
+                return Ok(status(StatusCode::BAD_REQUEST));
+            };
+            let id = EntityId { index, generation };
+            let components = ctx
+                .run_on_main_thread(move |ctx| inspect::read_entity(ctx.world, id))
+                .await;
+            inspected(components)
+        }
+        (Method::GET, ["resources"]) => {
+            let names = ctx
+                .run_on_main_thread(|ctx| inspect::resource_names(ctx.world))
+                .await;
+            json(&names)
+        }
+        (Method::GET, ["resources", short_name]) => {
+            let short_name = short_name.to_string();
+            let value = ctx
+                .run_on_main_thread(move |ctx| inspect::read_resource(ctx.world, &short_name))
+                .await;
+            inspected(value)
+        }
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(resp)
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|_| status(StatusCode::BAD_REQUEST))?;
+    serde_json::from_slice(&body).map_err(|e| bad_request(e.to_string()))
+}
+
+/// Subscribes to `query` on behalf of a new connection and streams its responses as
+/// server-sent events until the client goes away. The connection gets results routed
+/// and queued exactly like a WebSocket one.
+async fn stream_events(
+    mut ctx: TaskContext,
+    connections: Connections,
+    mut query: QuerySubReq,
+) -> Response<Body> {
+    let opened = ctx
+        .run_on_main_thread({
+            let connections = connections.clone();
+            move |ctx| -> Result<_, RequestError> {
+                // Checked before opening so a rejected request leaves no connection behind
+                query.validate(ctx.world)?;
+                let (id, queue, stats) = connections.open(ConnectOptions::default());
+                // Named after the connection so admin reports and metrics can tell streams apart
+                if query.id.is_empty() {
+                    query.id = format!("sse-{id}");
+                }
+                let api = ctx.world.resource::<EcsSubApi>();
+                api.connect(id);
+                let query_id = query.id.clone();
+                api.subscribe_components_for(Some(id), query, ctx.world)?;
+                Ok((id, query_id, queue, stats))
+            }
+        })
+        .await;
+    let (id, query_id, queue, stats) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    info!("New SSE connection {}", id);
+
+    let (mut sender, body) = Body::channel();
+    let writer = async move {
+        while let Some(msg) = queue.pop().await {
+            // Everything else routed to the connection, e.g. the app's own subscriptions
+            // broadcast to every client, is left out, the stream only carries the one
+            // query its client asked for
+            let ServerMsg::QuerySubResp(resp) = msg else {
+                continue;
+            };
+            if resp.id != query_id {
+                continue;
+            }
+            let event = format!("id: {}\ndata: {}\n\n", resp.stamp.seq, resp.json);
+            let len = event.len();
+            if sender.send_data(event.into()).await.is_err() {
+                break;
+            }
+            stats.record_sent(len);
+            connections.metrics().record_sent(len);
+        }
+        connections.close(id);
+        with_api(&mut ctx, move |api, _| api.disconnect(id)).await;
+        info!("Connection {} removed", id);
+    };
+    tokio::spawn(writer.instrument(info_span!("connection", id, transport = "sse")));
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+fn json(value: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+/// Unknown entities and resources are not found, anything else failed on our side
+fn inspected(value: Result<impl Serialize, RequestError>) -> Response<Body> {
+    match value {
+        Ok(value) => json(&value),
+        Err(e @ (RequestError::UnknownEntity(_) | RequestError::UnknownResource(_))) => {
+            error(StatusCode::NOT_FOUND, e)
+        }
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn error(status: StatusCode, e: RequestError) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(e.to_string()))
+        .unwrap()
+}
+
+fn bad_request(message: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,142 @@
+use bevy::{prelude::*, reflect::TypeRegistry, utils::HashMap};
+use bevy_ecs_dynamic::dynamic_query::DynamicQuery;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::RequestError,
+    field::{self, FieldPath, FieldValue},
+    registered,
+    registry::ComponentIdRegistry,
+    QueryId, ShortName, ShortNameFilter, Stamp,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AggregateOp {
+    Count,
+    Sum(FieldPath),
+    Min(FieldPath),
+    Max(FieldPath),
+}
+
+/// Subscription to a reduction over the matches of a query instead of the matches themselves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateSubReq {
+    pub id: QueryId,
+    pub fetch: Vec<ShortName>,
+    pub filter: Vec<ShortNameFilter>,
+    pub op: AggregateOp,
+    /// Reduce separately for every distinct value of this field
+    #[serde(default)]
+    pub group_by: Option<FieldPath>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateGroup {
+    /// Value of `group_by` for this group, `None` when ungrouped or the field was missing
+    pub key: Option<FieldValue>,
+    pub count: usize,
+    /// Result of the reduction, `None` for `Count` or if no match had a numeric value
+    pub value: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateSubResp {
+    pub id: QueryId,
+    pub stamp: Stamp,
+    pub groups: Vec<AggregateGroup>,
+}
+
+pub struct AggregateSubscription {
+    pub req: AggregateSubReq,
+    pub query: DynamicQuery,
+    /// Sequence number of the last response sent
+    pub seq: u64,
+    /// Change tick of the last response sent, the next one covers changes after it
+    pub last_sent_tick: u32,
+}
+
+impl AggregateSubReq {
+    /// Fetched components are only matched, not serialized, so they only need to be registered
+    pub fn validate(&self, world: &World) -> Result<(), RequestError> {
+        let fields = self.field().into_iter().chain(&self.group_by);
+        let short_names = self
+            .fetch
+            .iter()
+            .map(String::as_str)
+            .chain(
+                self.filter
+                    .iter()
+                    .map(|filter| filter.short_name().as_str()),
+            )
+            .chain(fields.map(|path| field::component(path)));
+        for short_name in short_names {
+            registered(world, short_name)?;
+        }
+        Ok(())
+    }
+
+    fn field(&self) -> Option<&FieldPath> {
+        match &self.op {
+            AggregateOp::Count => None,
+            AggregateOp::Sum(field) | AggregateOp::Min(field) | AggregateOp::Max(field) => {
+                Some(field)
+            }
+        }
+    }
+
+    pub fn run(
+        &self,
+        world: &World,
+        type_registry: &TypeRegistry,
+        registry: &ComponentIdRegistry,
+        dyn_query: &mut DynamicQuery,
+    ) -> AggregateSubResp {
+        let field = self.field();
+
+        // Keyed by the group key's json since `FieldValue` holds floats and isn't `Hash`
+        let mut groups = HashMap::<String, AggregateGroup>::default();
+        for raw in dyn_query.iter(world) {
+            let key = self.group_by.as_ref().and_then(|path| {
+                field::resolve(world, type_registry, registry, raw.entity, path)
+                    .and_then(FieldValue::from_reflect)
+            });
+            let group = groups
+                .entry(serde_json::to_string(&key).unwrap())
+                .or_insert_with(|| AggregateGroup {
+                    key,
+                    count: 0,
+                    value: None,
+                });
+            group.count += 1;
+
+            let Some(value) = field
+                .and_then(|path| field::resolve(world, type_registry, registry, raw.entity, path))
+                .and_then(FieldValue::from_reflect)
+                .and_then(|value| value.as_f64())
+            else {
+                continue;
+            };
+            group.value = Some(match (&self.op, group.value) {
+                (_, None) => value,
+                (AggregateOp::Sum(_), Some(acc)) => acc + value,
+                (AggregateOp::Min(_), Some(acc)) => acc.min(value),
+                (AggregateOp::Max(_), Some(acc)) => acc.max(value),
+                (AggregateOp::Count, Some(acc)) => acc,
+            });
+        }
+
+        let mut groups: Vec<_> = groups.into_values().collect();
+        if groups.is_empty() && self.group_by.is_none() {
+            groups.push(AggregateGroup {
+                key: None,
+                count: 0,
+                value: None,
+            });
+        }
+        AggregateSubResp {
+            id: self.id.clone(),
+            stamp: Stamp::default(),
+            groups,
+        }
+    }
+}
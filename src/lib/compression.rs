@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Query-string key used by clients to request a compression scheme when connecting,
+/// e.g. `ws://localhost:3012/socket?compression=zstd`
+pub const COMPRESSION_PARAM: &str = "compression";
+
+/// Response header the server uses to confirm which scheme it will send frames with
+pub const COMPRESSION_HEADER: &str = "x-ecs-sub-compression";
+
+/// Application-level compression applied to whole serialized `ServerMsg` frames.
+///
+/// `None` frames are sent as text, everything else as binary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Picks the compression requested in a connect uri's query string, if any
+    pub fn from_query(query: &str) -> Option<Self> {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == COMPRESSION_PARAM)
+            .and_then(|(_, value)| Self::from_name(value))
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Zstd => zstd::encode_all(bytes, 0),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+        }
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Zstd => zstd::decode_all(bytes),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let payload = br#"{"QuerySubResp":{"matches":[[0,{"Location":{"city":"NYC"}}]]}}"#;
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(payload).unwrap();
+            assert_eq!(compression.decompress(&compressed).unwrap(), payload);
+        }
+        assert_eq!(
+            Compression::from_query("foo=bar&compression=lz4"),
+            Some(Compression::Lz4)
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Compression;
+
+pub const BATCH_PARAM: &str = "batch";
+
+/// Identifies a client connection for as long as the server runs
+pub type ConnectionId = u64;
+
+/// Per-connection options a client requests through the connect uri's query string,
+/// e.g. `ws://localhost:3012/socket?compression=zstd&batch=true`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectOptions {
+    pub compression: Compression,
+    /// Bundle every result produced in one frame into a single message so the client
+    /// can apply them atomically
+    pub batch: bool,
+}
+
+impl ConnectOptions {
+    pub fn from_query(query: &str) -> Self {
+        ConnectOptions {
+            compression: Compression::from_query(query).unwrap_or_default(),
+            batch: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .any(|(key, value)| key == BATCH_PARAM && value == "true"),
+        }
+    }
+
+    pub fn to_query(&self) -> String {
+        format!(
+            "{}={}&{}={}",
+            crate::compression::COMPRESSION_PARAM,
+            self.compression.name(),
+            BATCH_PARAM,
+            self.batch
+        )
+    }
+}
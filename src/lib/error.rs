@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{EntityId, ShortName};
+
+/// Why a client request was rejected. Sent back to the client in place of the response,
+/// the server keeps running.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestError {
+    /// No component was registered with `register` under this short name
+    UnknownComponent(ShortName),
+    /// No resource reflected with `#[reflect(Resource)]` is in the world under this short name
+    UnknownResource(ShortName),
+    /// The entity was despawned or never existed
+    UnknownEntity(EntityId),
+    /// The connection has no subscription under this id
+    UnknownSubscription(String),
+    /// No event was registered with `register_event` under this short name
+    UnknownEvent(ShortName),
+    /// The type has no `#[reflect(Serialize)]`, its values can't be sent to clients
+    NotSerializable(ShortName),
+    /// The type has no `#[reflect(Deserialize)]`, clients can't send it
+    NotDeserializable(ShortName),
+    /// The payload didn't deserialize into the expected type
+    InvalidPayload(String),
+    /// A value failed to serialize, its `Serialize` impl returned an error
+    Serialize(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::UnknownComponent(short_name) => {
+                write!(f, "unknown component '{short_name}'")
+            }
+            RequestError::UnknownResource(short_name) => {
+                write!(f, "unknown resource '{short_name}'")
+            }
+            RequestError::UnknownEntity(id) => {
+                write!(f, "unknown entity {}v{}", id.index, id.generation)
+            }
+            RequestError::UnknownSubscription(id) => write!(f, "unknown subscription '{id}'"),
+            RequestError::UnknownEvent(short_name) => write!(f, "unknown event '{short_name}'"),
+            RequestError::NotSerializable(short_name) => {
+                write!(f, "'{short_name}' doesn't reflect Serialize")
+            }
+            RequestError::NotDeserializable(short_name) => {
+                write!(f, "'{short_name}' doesn't reflect Deserialize")
+            }
+            RequestError::InvalidPayload(e) => write!(f, "invalid payload: {e}"),
+            RequestError::Serialize(e) => write!(f, "failed to serialize: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
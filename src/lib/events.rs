@@ -0,0 +1,127 @@
+use bevy::{
+    ecs::event::{Event, Events, ManualEventReader},
+    prelude::*,
+    reflect::TypeRegistry,
+    utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::any::TypeId;
+
+use crate::{error::RequestError, ShortName, Stamp};
+
+/// Type-erased `ManualEventReader`, one per event subscription so every client
+/// sees each event exactly once regardless of other readers
+pub trait DynEventReader: Send + Sync {
+    fn read(&mut self, world: &World, type_registry: &TypeRegistry) -> Vec<Box<RawValue>>;
+}
+
+struct TypedEventReader<T: Event>(ManualEventReader<T>);
+
+impl<T: Event + Reflect> DynEventReader for TypedEventReader<T> {
+    fn read(&mut self, world: &World, type_registry: &TypeRegistry) -> Vec<Box<RawValue>> {
+        let Some(events) = world.get_resource::<Events<T>>() else {
+            return Vec::new();
+        };
+        // Checked by `EventRegistry::new_reader`
+        let Some(reflect_serialize) =
+            type_registry.get_type_data::<ReflectSerialize>(TypeId::of::<T>())
+        else {
+            return Vec::new();
+        };
+        self.0
+            .iter(events)
+            .filter_map(|event| {
+                let value = reflect_serialize.get_serializable(event);
+                serde_json::value::to_raw_value(value.borrow())
+                    .map_err(|e| error!("Failed to serialize {}: {}", event.type_name(), e))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RegisteredEvent {
+    type_id: TypeId,
+    new_reader: fn() -> Box<dyn DynEventReader>,
+    send: fn(&mut World, Box<dyn Reflect>),
+}
+
+/// Maps event short names to the typed operations needed to read and send them
+#[derive(Default, Resource)]
+pub struct EventRegistry {
+    events: HashMap<ShortName, RegisteredEvent>,
+}
+
+impl EventRegistry {
+    pub fn register<T: Event + Reflect>(&mut self, short_name: impl Into<ShortName>) {
+        self.events.insert(
+            short_name.into(),
+            RegisteredEvent {
+                type_id: TypeId::of::<T>(),
+                new_reader: || Box::new(TypedEventReader::<T>(ManualEventReader::default())),
+                send: |world, event| world.send_event(event.take::<T>().unwrap()),
+            },
+        );
+    }
+
+    /// Fails if nothing is registered under `short_name` or the event can't be serialized
+    pub fn new_reader(
+        &self,
+        short_name: &str,
+        type_registry: &TypeRegistry,
+    ) -> Result<Box<dyn DynEventReader>, RequestError> {
+        let registered = self
+            .events
+            .get(short_name)
+            .ok_or_else(|| RequestError::UnknownEvent(short_name.into()))?;
+        if type_registry
+            .get_type_data::<ReflectSerialize>(registered.type_id)
+            .is_none()
+        {
+            return Err(RequestError::NotSerializable(short_name.into()));
+        }
+        Ok((registered.new_reader)())
+    }
+}
+
+/// Deserializes `payload` through the event's `ReflectDeserialize` and pushes it into
+/// the matching `Events<T>`. Fails if the event is unknown, doesn't reflect `Deserialize`
+/// or the payload doesn't fit it.
+pub fn send_event(
+    world: &mut World,
+    short_name: &str,
+    payload: &RawValue,
+) -> Result<(), RequestError> {
+    let registered = *world
+        .get_resource::<EventRegistry>()
+        .and_then(|registry| registry.events.get(short_name))
+        .ok_or_else(|| RequestError::UnknownEvent(short_name.into()))?;
+    let event = {
+        let type_registry = world.resource::<AppTypeRegistry>().read();
+        let reflect_deserialize = type_registry
+            .get_type_data::<ReflectDeserialize>(registered.type_id)
+            .ok_or_else(|| RequestError::NotDeserializable(short_name.into()))?;
+        reflect_deserialize
+            .deserialize(&mut serde_json::Deserializer::from_str(payload.get()))
+            .map_err(|e| RequestError::InvalidPayload(e.to_string()))?
+    };
+    (registered.send)(world, event);
+    Ok(())
+}
+
+pub struct EventSubscription {
+    pub reader: Box<dyn DynEventReader>,
+    /// Sequence number of the last response sent
+    pub seq: u64,
+    /// Change tick of the last response sent, the next one covers changes after it
+    pub last_sent_tick: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EventSubResp {
+    pub event: ShortName,
+    pub stamp: Stamp,
+    pub events: Vec<Box<RawValue>>,
+}
@@ -0,0 +1,116 @@
+use bevy::{
+    prelude::*,
+    reflect::{GetPath, TypeRegistry},
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::{reflect_ptr, registry::ComponentIdRegistry};
+
+/// Component short name optionally followed by a reflect path into it,
+/// e.g. `Location.city` or `Health`
+pub type FieldPath = String;
+
+/// Short name of the component a path starts at
+pub fn component(path: &str) -> &str {
+    path.split_once('.')
+        .map_or(path, |(component, _)| component)
+}
+
+/// Looks up the value at `path` on `entity`, `None` if it doesn't have the component or
+/// the component isn't registered at all
+pub fn resolve<'w>(
+    world: &'w World,
+    type_registry: &TypeRegistry,
+    registry: &ComponentIdRegistry,
+    entity: Entity,
+    path: &str,
+) -> Option<&'w dyn Reflect> {
+    let (component, field) = path.split_once('.').unwrap_or((path, ""));
+    let ptr = world
+        .get_entity(entity)?
+        .get_by_id(registry.try_short_name(component)?)?;
+    let reflect = reflect_ptr(type_registry, ptr, component);
+    if field.is_empty() {
+        Some(reflect)
+    } else {
+        reflect.path(field).ok()
+    }
+}
+
+/// Primitive field value that can be ordered and aggregated
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl FieldValue {
+    pub fn from_reflect(value: &dyn Reflect) -> Option<Self> {
+        macro_rules! num {
+            ($($ty:ty),*) => {
+                $(if let Some(v) = value.downcast_ref::<$ty>() {
+                    return Some(FieldValue::Num(*v as f64));
+                })*
+            };
+        }
+        num!(f32, f64, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+        if let Some(v) = value.downcast_ref::<String>() {
+            return Some(FieldValue::Str(v.clone()));
+        }
+        if let Some(v) = value.downcast_ref::<bool>() {
+            return Some(FieldValue::Bool(*v));
+        }
+        None
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Num(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SortBy {
+    pub field: FieldPath,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+impl SortBy {
+    /// Sorts by the field's value, entities without a comparable value go last
+    pub fn sort(
+        &self,
+        world: &World,
+        type_registry: &TypeRegistry,
+        registry: &ComponentIdRegistry,
+        entities: &mut Vec<Entity>,
+    ) {
+        let mut keyed: Vec<_> = entities
+            .drain(..)
+            .map(|entity| {
+                let key = resolve(world, type_registry, registry, entity, &self.field)
+                    .and_then(FieldValue::from_reflect);
+                (key, entity)
+            })
+            .collect();
+        keyed.sort_by(|(a, _), (b, _)| match (a, b) {
+            (Some(a), Some(b)) => {
+                let ord = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+                if self.descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+        entities.extend(keyed.into_iter().map(|(_, entity)| entity));
+    }
+}
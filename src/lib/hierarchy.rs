@@ -0,0 +1,141 @@
+use bevy::{prelude::*, reflect::TypeRegistry, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+    error::RequestError, fetch_entity, map_components, registry::ComponentIdRegistry,
+    validate_query, ComponentValue, EntityId, ShortName, ShortNameFilter,
+};
+
+/// Query applied to the children of each match of the enclosing query
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChildQuery {
+    pub fetch: Vec<ShortName>,
+    pub filter: Vec<ShortNameFilter>,
+    /// Search the whole subtree instead of only direct children
+    #[serde(default)]
+    pub descendants: bool,
+    /// Applied in turn to the children of every match of this query
+    #[serde(default)]
+    pub children: Option<Box<ChildQuery>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChildMatch<V = Box<RawValue>> {
+    pub entity: EntityId,
+    pub components: HashMap<ShortName, V>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ChildMatch<V>>,
+}
+
+impl<V> ChildMatch<V> {
+    pub fn map_values<W>(self, f: &mut impl FnMut(V) -> W) -> ChildMatch<W> {
+        ChildMatch {
+            entity: self.entity,
+            components: map_components(self.components, f),
+            children: self
+                .children
+                .into_iter()
+                .map(|child| child.map_values(f))
+                .collect(),
+        }
+    }
+}
+
+impl ChildQuery {
+    pub fn validate(&self, world: &World) -> Result<(), RequestError> {
+        validate_query(world, &self.fetch, &self.filter)?;
+        match &self.children {
+            Some(children) => children.validate(world),
+            None => Ok(()),
+        }
+    }
+
+    pub fn run<'w, V: ComponentValue<'w>>(
+        &self,
+        world: &'w World,
+        type_registry: &'w TypeRegistry,
+        registry: &ComponentIdRegistry,
+        parent: Entity,
+        last_change_tick: u32,
+    ) -> serde_json::Result<Vec<ChildMatch<V>>> {
+        let mut matches = Vec::new();
+        self.run_into(
+            world,
+            type_registry,
+            registry,
+            parent,
+            last_change_tick,
+            &mut matches,
+        )?;
+        Ok(matches)
+    }
+
+    fn run_into<'w, V: ComponentValue<'w>>(
+        &self,
+        world: &'w World,
+        type_registry: &'w TypeRegistry,
+        registry: &ComponentIdRegistry,
+        parent: Entity,
+        last_change_tick: u32,
+        matches: &mut Vec<ChildMatch<V>>,
+    ) -> serde_json::Result<()> {
+        let Some(children) = world.get::<Children>(parent) else {
+            return Ok(());
+        };
+        for &child in children.iter() {
+            let Some(entity) = world.get_entity(child) else {
+                continue;
+            };
+            if let Some(components) = fetch_entity(
+                world,
+                type_registry,
+                registry,
+                &entity,
+                &self.fetch,
+                &self.filter,
+                last_change_tick,
+            ) {
+                let children = match &self.children {
+                    Some(query) => {
+                        query.run(world, type_registry, registry, child, last_change_tick)?
+                    }
+                    None => Vec::new(),
+                };
+                matches.push(ChildMatch {
+                    entity: EntityId::from(child),
+                    components: components?,
+                    children,
+                });
+            }
+            if self.descendants {
+                self.run_into(
+                    world,
+                    type_registry,
+                    registry,
+                    child,
+                    last_change_tick,
+                    matches,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every entity below `root`, walking down `Children` breadth first
+pub fn descendants(world: &World, root: Entity) -> Vec<Entity> {
+    let mut descendants = Vec::new();
+    let mut parent = root;
+    let mut next = 0;
+    loop {
+        if let Some(children) = world.get::<Children>(parent) {
+            descendants.extend(children.iter().copied());
+        }
+        let Some(&child) = descendants.get(next) else {
+            return descendants;
+        };
+        parent = child;
+        next += 1;
+    }
+}
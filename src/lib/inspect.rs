@@ -0,0 +1,87 @@
+use bevy::{prelude::*, reflect::TypeRegistry, utils::HashMap};
+use serde_json::value::RawValue;
+
+use crate::{error::RequestError, reflect_ptr, registry::ComponentIdRegistry, EntityId, ShortName};
+
+/// Short names of every registered component, sorted
+pub fn component_names(world: &World) -> Vec<ShortName> {
+    let registry = world.resource::<ComponentIdRegistry>();
+    let mut names: Vec<ShortName> = registry
+        .short_names()
+        .map(|(short_name, _)| short_name.clone())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Every registered component of the entity that reflects `Serialize`
+pub fn read_entity(
+    world: &World,
+    id: EntityId,
+) -> Result<HashMap<ShortName, Box<RawValue>>, RequestError> {
+    let entity = world
+        .get_entity(Entity::from(id))
+        .ok_or(RequestError::UnknownEntity(id))?;
+    let type_registry = world.resource::<AppTypeRegistry>().read();
+    let registry = world.resource::<ComponentIdRegistry>();
+    let mut components = HashMap::default();
+    for (short_name, component_id) in registry.short_names() {
+        let Some(ptr) = entity.get_by_id(component_id) else {
+            continue;
+        };
+        match to_json(
+            &type_registry,
+            reflect_ptr(&type_registry, ptr, short_name),
+            short_name,
+        ) {
+            Ok(value) => {
+                components.insert(short_name.clone(), value);
+            }
+            Err(RequestError::NotSerializable(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(components)
+}
+
+/// Short names of the resources reflected with `#[reflect(Resource)]` that are present
+/// in the world, sorted
+pub fn resource_names(world: &World) -> Vec<ShortName> {
+    let type_registry = world.resource::<AppTypeRegistry>().read();
+    let mut names: Vec<ShortName> = type_registry
+        .iter()
+        .filter(|registration| {
+            registration
+                .data::<ReflectResource>()
+                .map_or(false, |reflect| reflect.reflect(world).is_some())
+        })
+        .map(|registration| registration.short_name().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Reads a resource reflected with `#[reflect(Resource)]`, `UnknownResource` if the type
+/// is unknown or the resource isn't in the world
+pub fn read_resource(world: &World, short_name: &str) -> Result<Box<RawValue>, RequestError> {
+    let type_registry = world.resource::<AppTypeRegistry>().read();
+    let value = type_registry
+        .get_with_short_name(short_name)
+        .and_then(|registration| registration.data::<ReflectResource>())
+        .and_then(|reflect| reflect.reflect(world))
+        .ok_or_else(|| RequestError::UnknownResource(short_name.into()))?;
+    to_json(&type_registry, value, short_name)
+}
+
+/// Serializes through the type's own `Serialize` impl, same as query responses
+fn to_json(
+    type_registry: &TypeRegistry,
+    value: &dyn Reflect,
+    short_name: &str,
+) -> Result<Box<RawValue>, RequestError> {
+    let reflect_serialize = type_registry
+        .get_type_data::<ReflectSerialize>(value.type_id())
+        .ok_or_else(|| RequestError::NotSerializable(short_name.into()))?;
+    serde_json::value::to_raw_value(reflect_serialize.get_serializable(value).borrow())
+        .map_err(|e| RequestError::Serialize(e.to_string()))
+}
@@ -1,30 +1,128 @@
 #![allow(unused, dead_code)]
 
+pub mod aggregate;
+pub mod compression;
+pub mod connection;
+pub mod error;
+pub mod events;
+pub mod field;
+pub mod hierarchy;
+pub mod inspect;
+pub mod lifecycle;
+pub mod metrics;
+pub mod outbound;
+pub mod protocol;
 pub mod registry;
+pub mod server;
+pub mod spatial;
+pub mod stats;
+pub mod transport;
 
+use self::aggregate::{AggregateSubReq, AggregateSubResp, AggregateSubscription};
+use self::error::RequestError;
+use self::events::{EventRegistry, EventSubResp, EventSubscription};
+use self::field::SortBy;
+use self::hierarchy::{ChildMatch, ChildQuery};
+use self::lifecycle::{
+    ClientConnected, ClientDisconnected, LifecycleEvent, SubscriptionAdded, SubscriptionKind,
+    SubscriptionRemoved, SubscriptionReq,
+};
 use self::registry::ComponentIdRegistry;
+use self::spatial::Region;
+use self::stats::{SubscriptionInfo, SubscriptionStats};
 use bevy::reflect::{erased_serde, GetTypeRegistration};
 use bevy::{
-    ecs::component::ComponentId,
+    core::FrameCount,
+    ecs::{component::ComponentId, world::EntityRef},
     prelude::*,
+    ptr::Ptr,
     reflect::{ReflectFromPtr, TypeRegistry},
-    utils::HashMap,
+    tasks::{ComputeTaskPool, TaskPool},
+    utils::{tracing::field, HashMap, HashSet},
 };
 use bevy_ecs_dynamic::dynamic_query::{self, DynamicQuery, FetchKind, FetchResult, FilterKind};
+pub use compression::Compression;
+pub use connection::{ConnectOptions, ConnectionId};
 pub use registry::{RegistryExt, ShortName};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_json::value::RawValue;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{any::TypeId, io};
 
 fn main() {
     println!("Hello, world!");
 }
 
+/// Subscriptions are keyed by their owner too, so clients picking the same id don't clash
+/// and can only touch their own. `None` owns the subscriptions made by the app itself.
+pub type SubKey<K = QueryId> = (Option<ConnectionId>, K);
+
 #[derive(Default, Resource)]
 pub struct EcsSubApi {
-    pub queries: Box<RwLock<HashMap<QueryId, (QuerySubReq, DynamicQuery)>>>,
+    pub queries: Box<RwLock<HashMap<SubKey, Subscription>>>,
+    pub events: Box<RwLock<HashMap<SubKey<ShortName>, EventSubscription>>>,
+    pub aggregates: Box<RwLock<HashMap<SubKey, AggregateSubscription>>>,
+    /// Waiting to be sent as Bevy events by `lifecycle::flush_lifecycle_events`
+    pub lifecycle: Box<Mutex<Vec<LifecycleEvent>>>,
+}
+
+pub struct Subscription {
+    pub req: QuerySubReq,
+    /// Client that made the subscription, dropped together with it on disconnect
+    pub connection: Option<ConnectionId>,
+    /// Built without the `Changed` filters, those are checked against `last_run_tick`
+    pub query: DynamicQuery,
+    /// Entities the client knows about, checked on every run so despawns can be reported
+    pub sent: HashSet<Entity>,
+    /// Change tick of the previous run, `Changed` filters compare against it so changes
+    /// made between throttled runs or across a modify are not missed
+    pub last_run_tick: u32,
+    /// Set on subscribe and on resync, the next run then sends every match
+    pub needs_snapshot: bool,
+    /// Sequence number of the last response sent
+    pub seq: u64,
+    pub stats: Arc<SubscriptionStats>,
+    frames_since_run: u32,
+    /// Reused by `write_query` so the json of every run lands in the same allocation
+    buf: Vec<u8>,
+}
+
+impl Subscription {
+    fn new(connection: Option<ConnectionId>, query: QuerySubReq, world: &World) -> Self {
+        let dyn_query = build_query(world, &query.fetch, &without_changed(&query.filter));
+        // Watched entities are reported as despawned even if they never matched
+        let sent = query
+            .entities
+            .iter()
+            .flatten()
+            .map(|id| Entity::from(*id))
+            .collect();
+        Subscription {
+            req: query,
+            connection,
+            query: dyn_query,
+            sent,
+            last_run_tick: world.last_change_tick(),
+            needs_snapshot: true,
+            seq: 0,
+            stats: Arc::default(),
+            // Saturated so the first frame always runs
+            frames_since_run: u32::MAX,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Counts a frame and returns whether the subscription is due to run on it
+    fn tick(&mut self) -> bool {
+        self.frames_since_run = self.frames_since_run.saturating_add(1);
+        if self.frames_since_run < self.req.interval.unwrap_or(1) {
+            return false;
+        }
+        self.frames_since_run = 0;
+        true
+    }
 }
 
 impl EcsSubApi {
@@ -33,84 +131,889 @@ impl EcsSubApi {
     pub fn subscribe_resource(&self, res: ResourceSubReq) {
         todo!()
     }
+    /// Panics on short names that aren't registered, clients go through
+    /// `subscribe_components_for` which rejects them instead
     pub fn subscribe_components(&self, query: QuerySubReq, world: &World) {
-        let registry = world.get_resource::<ComponentIdRegistry>().unwrap();
-        let component_fetches = query
+        self.subscribe_components_for(None, query, world).unwrap()
+    }
+
+    pub fn subscribe_components_for(
+        &self,
+        connection: Option<ConnectionId>,
+        query: QuerySubReq,
+        world: &World,
+    ) -> Result<(), RequestError> {
+        query.validate(world)?;
+        let replaced = self.queries.write().unwrap().insert(
+            (connection, query.id.clone()),
+            Subscription::new(connection, query.clone(), world),
+        );
+        if replaced.is_some() {
+            self.emit_removed(connection, SubscriptionKind::Query, query.id.clone());
+        }
+        self.emit(LifecycleEvent::SubscriptionAdded(SubscriptionAdded {
+            connection,
+            req: SubscriptionReq::Query(query),
+        }));
+        Ok(())
+    }
+
+    /// Runs a query a single time without subscribing to it. Being the first run, the
+    /// response is a snapshot of every match.
+    pub fn query_once(
+        &self,
+        query: QuerySubReq,
+        world: &World,
+    ) -> Result<QuerySubResp, RequestError> {
+        query.validate(world)?;
+        let mut sub = Subscription::new(None, query, world);
+        self.run_query_internal(world, &mut sub)
+            .map_err(|e| RequestError::Serialize(e.to_string()))
+    }
+
+    /// Replaces the request of an existing subscription, only rebuilding its query if the
+    /// fetch or filters changed so despawn and change tracking carry over. Anything that
+    /// can make unchanged entities match makes the next response a snapshot.
+    /// Returns the request now in effect.
+    pub fn modify_subscription(&self, query: QuerySubReq, world: &World) -> QuerySubReq {
+        self.modify_subscription_for(None, query, world).unwrap()
+    }
+
+    /// Subscribes on behalf of `connection` if there is nothing to modify yet
+    pub fn modify_subscription_for(
+        &self,
+        connection: Option<ConnectionId>,
+        query: QuerySubReq,
+        world: &World,
+    ) -> Result<QuerySubReq, RequestError> {
+        query.validate(world)?;
+        let mut queries = self.queries.write().unwrap();
+        let Some(sub) = queries.get_mut(&(connection, query.id.clone())) else {
+            drop(queries);
+            self.subscribe_components_for(connection, query.clone(), world)?;
+            return Ok(query);
+        };
+        // `last_run_tick` is kept, changes since the last run still come through
+        let filter = without_changed(&query.filter);
+        let refiltered = filter != without_changed(&sub.req.filter);
+        if sub.req.fetch != query.fetch || refiltered {
+            sub.query = build_query(world, &query.fetch, &filter);
+        }
+        // The client has no values yet for newly fetched components or newly matching
+        // entities that didn't change, e.g. those entering a moved region or page
+        let gains_fetch = query
             .fetch
             .iter()
-            .map(|short_name| FetchKind::Ref(registry.short_name(short_name)))
-            .collect();
-        let filters = query
-            .filter
-            .iter()
-            .map(|filter| filter.resolve_components(registry))
-            .collect();
+            .any(|short_name| !sub.req.fetch.contains(short_name));
+        let old = &sub.req;
+        let rescoped = query.entities != old.entities
+            || query.root != old.root
+            || query.region != old.region
+            || query.sort != old.sort
+            || query.limit != old.limit
+            || query.offset != old.offset
+            || query.children != old.children
+            || query.layout != old.layout;
+        if gains_fetch || refiltered || rescoped {
+            sub.needs_snapshot = true;
+        }
+        sub.req = query;
+        Ok(sub.req.clone())
+    }
 
-        let dyn_query = DynamicQuery::new(world, component_fetches, filters).unwrap();
-        self.queries
+    /// Makes the next run of a subscription send a full snapshot again, e.g. after the
+    /// client noticed a gap in the change stream
+    pub fn resync(
+        &self,
+        connection: Option<ConnectionId>,
+        id: &QueryId,
+    ) -> Result<(), RequestError> {
+        let mut queries = self.queries.write().unwrap();
+        let sub = queries
+            .get_mut(&(connection, id.clone()))
+            .ok_or_else(|| RequestError::UnknownSubscription(id.clone()))?;
+        sub.needs_snapshot = true;
+        Ok(())
+    }
+
+    /// Moves a subscription's region of interest without rebuilding its query. Entities
+    /// entering the region may not have changed, so the next response is a snapshot.
+    pub fn set_region(
+        &self,
+        connection: Option<ConnectionId>,
+        id: &QueryId,
+        region: Option<Region>,
+    ) -> Result<(), RequestError> {
+        let mut queries = self.queries.write().unwrap();
+        let sub = queries
+            .get_mut(&(connection, id.clone()))
+            .ok_or_else(|| RequestError::UnknownSubscription(id.clone()))?;
+        if sub.req.region != region {
+            sub.needs_snapshot = true;
+            sub.req.region = region;
+        }
+        Ok(())
+    }
+
+    pub fn subscribe_aggregate(
+        &self,
+        aggregate: AggregateSubReq,
+        world: &World,
+    ) -> Result<(), RequestError> {
+        self.subscribe_aggregate_for(None, aggregate, world)
+    }
+
+    pub fn subscribe_aggregate_for(
+        &self,
+        connection: Option<ConnectionId>,
+        aggregate: AggregateSubReq,
+        world: &World,
+    ) -> Result<(), RequestError> {
+        aggregate.validate(world)?;
+        let dyn_query = build_query(world, &aggregate.fetch, &aggregate.filter);
+        let replaced = self.aggregates.write().unwrap().insert(
+            (connection, aggregate.id.clone()),
+            AggregateSubscription {
+                req: aggregate.clone(),
+                query: dyn_query,
+                seq: 0,
+                last_sent_tick: world.read_change_tick(),
+            },
+        );
+        if replaced.is_some() {
+            self.emit_removed(
+                connection,
+                SubscriptionKind::Aggregate,
+                aggregate.id.clone(),
+            );
+        }
+        self.emit(LifecycleEvent::SubscriptionAdded(SubscriptionAdded {
+            connection,
+            req: SubscriptionReq::Aggregate(aggregate),
+        }));
+        Ok(())
+    }
+
+    /// Drops the query or aggregate subscription `connection` made under `id`
+    pub fn unsubscribe(
+        &self,
+        connection: Option<ConnectionId>,
+        id: &QueryId,
+    ) -> Result<(), RequestError> {
+        let key = (connection, id.clone());
+        let query = self.queries.write().unwrap().remove(&key);
+        let aggregate = self.aggregates.write().unwrap().remove(&key);
+        if query.is_some() {
+            self.emit_removed(connection, SubscriptionKind::Query, id.clone());
+        }
+        if aggregate.is_some() {
+            self.emit_removed(connection, SubscriptionKind::Aggregate, id.clone());
+        }
+        if query.is_none() && aggregate.is_none() {
+            return Err(RequestError::UnknownSubscription(id.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn connect(&self, connection: ConnectionId) {
+        self.emit(LifecycleEvent::ClientConnected(ClientConnected {
+            connection,
+        }));
+    }
+
+    /// Drops every query, aggregate and event subscription the client made
+    pub fn disconnect(&self, connection: ConnectionId) {
+        let owner = Some(connection);
+        let mut removed = Vec::new();
+        self.queries.write().unwrap().retain(|(owned_by, id), _| {
+            if *owned_by == owner {
+                removed.push((SubscriptionKind::Query, id.clone()));
+            }
+            *owned_by != owner
+        });
+        self.aggregates
+            .write()
+            .unwrap()
+            .retain(|(owned_by, id), _| {
+                if *owned_by == owner {
+                    removed.push((SubscriptionKind::Aggregate, id.clone()));
+                }
+                *owned_by != owner
+            });
+        self.events
+            .write()
+            .unwrap()
+            .retain(|(owned_by, short_name), _| {
+                if *owned_by == owner {
+                    removed.push((SubscriptionKind::Events, short_name.clone()));
+                }
+                *owned_by != owner
+            });
+        for (kind, id) in removed {
+            self.emit_removed(owner, kind, id);
+        }
+        self.emit(LifecycleEvent::ClientDisconnected(ClientDisconnected {
+            connection,
+        }));
+    }
+
+    fn emit(&self, event: LifecycleEvent) {
+        self.lifecycle.lock().unwrap().push(event);
+    }
+
+    fn emit_removed(&self, connection: Option<ConnectionId>, kind: SubscriptionKind, id: QueryId) {
+        self.emit(LifecycleEvent::SubscriptionRemoved(SubscriptionRemoved {
+            connection,
+            kind,
+            id,
+        }));
+    }
+
+    /// Fails if the event is unknown, e.g. because the app never registered any event
+    pub fn subscribe_events(
+        &self,
+        short_name: ShortName,
+        world: &World,
+    ) -> Result<(), RequestError> {
+        self.subscribe_events_for(None, short_name, world)
+    }
+
+    pub fn subscribe_events_for(
+        &self,
+        connection: Option<ConnectionId>,
+        short_name: ShortName,
+        world: &World,
+    ) -> Result<(), RequestError> {
+        let reader = world
+            .get_resource::<EventRegistry>()
+            .ok_or_else(|| RequestError::UnknownEvent(short_name.clone()))?
+            .new_reader(&short_name, &world.resource::<AppTypeRegistry>().read())?;
+        let replaced = self.events.write().unwrap().insert(
+            (connection, short_name.clone()),
+            EventSubscription {
+                reader,
+                seq: 0,
+                last_sent_tick: world.read_change_tick(),
+            },
+        );
+        if replaced.is_some() {
+            self.emit_removed(connection, SubscriptionKind::Events, short_name.clone());
+        }
+        self.emit(LifecycleEvent::SubscriptionAdded(SubscriptionAdded {
+            connection,
+            req: SubscriptionReq::Events(short_name),
+        }));
+        Ok(())
+    }
+
+    pub fn unsubscribe_events(
+        &self,
+        connection: Option<ConnectionId>,
+        short_name: &ShortName,
+    ) -> Result<(), RequestError> {
+        self.events
             .write()
             .unwrap()
-            .insert(query.id.clone(), (query, dyn_query));
+            .remove(&(connection, short_name.clone()))
+            .ok_or_else(|| RequestError::UnknownSubscription(short_name.clone()))?;
+        self.emit_removed(connection, SubscriptionKind::Events, short_name.clone());
+        Ok(())
+    }
+
+    /// Drains every event subscription, skipping those with nothing new this frame.
+    /// Each response comes with the connection that subscribed.
+    pub fn run_all_event_subs(&self, world: &World) -> Vec<(Option<ConnectionId>, EventSubResp)> {
+        let type_registry = &*world.get_resource::<AppTypeRegistry>().unwrap().read();
+        let mut events = self.events.write().unwrap();
+        par_map(
+            events.iter_mut().collect(),
+            |((connection, short_name), sub)| {
+                let events = sub.reader.read(world, type_registry);
+                if events.is_empty() {
+                    return None;
+                }
+                sub.seq += 1;
+                let resp = EventSubResp {
+                    event: short_name.clone(),
+                    events,
+                    stamp: Stamp::new(world, sub.last_sent_tick, sub.seq),
+                };
+                sub.last_sent_tick = resp.stamp.tick;
+                Some((*connection, resp))
+            },
+        )
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Each response comes with the connection that subscribed
+    pub fn run_all_aggregates(
+        &self,
+        world: &World,
+    ) -> Vec<(Option<ConnectionId>, AggregateSubResp)> {
+        let type_registry = &*world.get_resource::<AppTypeRegistry>().unwrap().read();
+        let registry = world.get_resource::<ComponentIdRegistry>().unwrap();
+        let mut aggregates = self.aggregates.write().unwrap();
+        par_map(aggregates.iter_mut().collect(), |((connection, _), sub)| {
+            sub.seq += 1;
+            let mut resp = sub.req.run(world, type_registry, registry, &mut sub.query);
+            resp.stamp = Stamp::new(world, sub.last_sent_tick, sub.seq);
+            sub.last_sent_tick = resp.stamp.tick;
+            (*connection, resp)
+        })
     }
 
+    /// Responses that fail to serialize are logged and left out
     pub fn run_all_queries(&self, world: &World) -> Vec<QuerySubResp> {
+        let mut resps = Vec::new();
+        self.for_each_due(|sub| match self.run_query_internal(world, sub) {
+            Ok(resp) => resps.push(resp),
+            Err(e) => error!("Failed to serialize {}: {}", sub.req.id, e),
+        });
+        resps
+    }
+
+    /// Calls `f` with the json of every subscription due to run this frame, copied out of
+    /// the subscription's reused buffer. The subscriptions are run and serialized in
+    /// parallel on the `ComputeTaskPool`, all only reading the world. Responses that fail
+    /// to serialize are logged and skipped.
+    pub fn write_all_queries(&self, world: &World, mut f: impl FnMut(&Subscription, QuerySubJson)) {
+        let type_registry = &*world.resource::<AppTypeRegistry>().read();
         let mut queries = self.queries.write().unwrap();
-        queries
-            .iter_mut()
-            .map(|(_id, (query, dyn_query))| self.run_query_internal(world, &query, dyn_query))
-            .collect()
+        let _span = info_span!("write_all_queries", subscriptions = queries.len()).entered();
+        let mut due: Vec<&mut Subscription> =
+            queries.values_mut().filter(|sub| sub.tick()).collect();
+        let written = par_map(due.iter_mut().collect(), |sub| {
+            self.write_resp(world, type_registry, sub)
+                .and_then(|stamp| QuerySubJson::new(sub.req.id.clone(), stamp, &sub.buf))
+        });
+        for (sub, written) in due.into_iter().zip(written) {
+            match written {
+                Ok(json) => f(sub, json),
+                Err(e) => error!("Failed to serialize {}: {}", sub.req.id, e),
+            }
+        }
     }
 
+    /// The one loop over the subscriptions, counting a frame for each and calling `f` with
+    /// those due to run on it
+    fn for_each_due(&self, mut f: impl FnMut(&mut Subscription)) {
+        let mut queries = self.queries.write().unwrap();
+        let _span = info_span!("run_all_queries", subscriptions = queries.len()).entered();
+        for sub in queries.values_mut().filter(|sub| sub.tick()) {
+            f(sub);
+        }
+    }
+
+    /// Runs a subscription the app made itself. Panics if a component fails to serialize.
     pub fn run_query(&self, world: &World, id: &QueryId) -> QuerySubResp {
         let mut queries = self.queries.write().unwrap();
-        let (query, dyn_query) = queries.get_mut(id).unwrap();
-        self.run_query_internal(world, query, dyn_query)
+        let sub = queries.get_mut(&(None, id.clone())).unwrap();
+        self.run_query_internal(world, sub).unwrap()
     }
 
-    pub fn run_query_internal(
+    /// Runs the query and writes its response as json into the subscription's buffer, which
+    /// is reused across runs. Components are serialized straight from the world through
+    /// their `ReflectSerialize`, without an intermediate `String` each.
+    pub fn write_query<'s>(
         &self,
         world: &World,
-        query: &QuerySubReq,
-        dyn_query: &mut DynamicQuery,
-    ) -> QuerySubResp {
-        let type_registry = &*world.get_resource::<AppTypeRegistry>().unwrap().read();
-        let matches = dyn_query
-            .iter(world)
-            .map(|raw| {
-                let components = raw
-                    .items
-                    .iter()
-                    .zip(query.fetch.iter())
-                    .map(|(fetch_res, short_name)| {
-                        let FetchResult::Ref(ptr) = fetch_res else {
-                            unimplemented!();
-                        };
-                        let type_id = type_registry
-                            .get_with_short_name(short_name)
-                            .unwrap()
-                            .type_id();
-                        let reflect = type_registry
-                            .get_type_data::<ReflectFromPtr>(type_id)
-                            .unwrap();
-
-                        // SAFETY:
-                        // `val` is a pointer to value of the type that the `ReflectFromPtr` was constructed for,
-                        // because the mapping from `ComponentId -> TypeId` is immutable and `ReflectFromPtr` is checked to be
-                        // for the type of the `WorldBase`'s type id.
-                        let reflect = unsafe { reflect.as_reflect_ptr(*ptr) };
-                        let value = type_registry
-                            .get_type_data::<ReflectSerialize>(type_id)
-                            .unwrap()
-                            .get_serializable(reflect);
-                        let serialized = RawValue::from_string(value.borrow().to_json()).unwrap();
-                        (short_name.clone(), serialized)
+        sub: &'s mut Subscription,
+    ) -> serde_json::Result<&'s [u8]> {
+        let type_registry = world.resource::<AppTypeRegistry>().read();
+        self.write_resp(world, &type_registry, sub)?;
+        Ok(&sub.buf)
+    }
+
+    fn write_resp(
+        &self,
+        world: &World,
+        type_registry: &TypeRegistry,
+        sub: &mut Subscription,
+    ) -> serde_json::Result<Stamp> {
+        let resp: QuerySubResp<Streamed> = self.run_query_with(world, type_registry, sub)?;
+        let started = Instant::now();
+        sub.buf.clear();
+        serde_json::to_writer(&mut sub.buf, &resp)?;
+        sub.stats.record_serialize(started.elapsed());
+        Ok(resp.stamp)
+    }
+
+    pub fn run_query_internal<V: for<'w> ComponentValue<'w>>(
+        &self,
+        world: &World,
+        sub: &mut Subscription,
+    ) -> serde_json::Result<QuerySubResp<V>> {
+        let type_registry = world.resource::<AppTypeRegistry>().read();
+        self.run_query_with(world, &type_registry, sub)
+    }
+
+    fn run_query_with<'w, V: ComponentValue<'w>>(
+        &self,
+        world: &'w World,
+        type_registry: &'w TypeRegistry,
+        sub: &mut Subscription,
+    ) -> serde_json::Result<QuerySubResp<V>> {
+        let started = Instant::now();
+        let Subscription {
+            req: query,
+            query: dyn_query,
+            sent,
+            last_run_tick,
+            needs_snapshot,
+            seq,
+            stats,
+            ..
+        } = sub;
+        let span = info_span!("run_query", id = %query.id, entities = field::Empty).entered();
+        *seq += 1;
+        let last_change_tick = *last_run_tick;
+        *last_run_tick = world.read_change_tick();
+
+        let filters = without_changed(&query.filter);
+        let snapshot = std::mem::take(needs_snapshot) || filters.len() == query.filter.len();
+        let changed: Vec<&ShortNameFilter> = if snapshot {
+            Vec::new()
+        } else {
+            query
+                .filter
+                .iter()
+                .filter(|filter| matches!(filter, ShortNameFilter::Changed(_)))
+                .collect()
+        };
+        let registry = world.get_resource::<ComponentIdRegistry>().unwrap();
+        // Checked here rather than in the `DynamicQuery`, against this subscription's own
+        // previous run
+        let is_changed = |entity: Entity| {
+            changed.is_empty()
+                || world.get_entity(entity).map_or(false, |entity| {
+                    changed.iter().all(|filter| {
+                        filter.matches_entity(world, registry, &entity, last_change_tick)
+                    })
+                })
+        };
+
+        // A stale generation no longer resolves, even if its index has since been reused
+        let mut despawned = Vec::new();
+        sent.retain(|entity| {
+            let alive = world.get_entity(*entity).is_some();
+            if !alive {
+                despawned.push(EntityId::from(*entity));
+            }
+            alive
+        });
+
+        // Walked down once from the root, in breadth first order
+        let subtree = query
+            .root
+            .map(|root| hierarchy::descendants(world, Entity::from(root)));
+        let in_subtree: Option<HashSet<Entity>> = subtree
+            .as_ref()
+            .map(|subtree| subtree.iter().copied().collect());
+        let mut matched_now = HashSet::new();
+        let run_children = |parent: Entity| match &query.children {
+            Some(child_query) => {
+                child_query.run(world, type_registry, registry, parent, last_change_tick)
+            }
+            None => Ok(Vec::new()),
+        };
+        let fetch = &query.fetch;
+        let region = query.region;
+        let in_scope = |entity: Entity| {
+            in_subtree
+                .as_ref()
+                .map_or(true, |subtree| subtree.contains(&entity))
+                && region.map_or(true, |region| region.contains_entity(world, entity))
+        };
+        let paged = query.sort.is_some() || query.limit.is_some() || query.offset > 0;
+        let mut total = None;
+        // Only entity ids are collected up front, components are then fetched directly
+        // for the watched, paged or subtree entities so the cost scales with what is returned
+        let direct = query.entities.is_some() || paged || subtree.is_some();
+        type Matched<V> = serde_json::Result<(Entity, HashMap<ShortName, V>)>;
+        let matched: Box<dyn Iterator<Item = Matched<V>> + '_> = if direct {
+            let candidates = |entities: &mut dyn Iterator<Item = Entity>| -> Vec<Entity> {
+                entities
+                    .filter(|entity| {
+                        world.get_entity(*entity).map_or(false, |entity| {
+                            fetch.iter().all(|short_name| {
+                                entity.contains_id(registry.short_name(short_name))
+                            }) && filters.iter().all(|filter| {
+                                filter.matches_entity(world, registry, &entity, last_change_tick)
+                            })
+                        }) && is_changed(*entity)
                     })
+                    .collect()
+            };
+            let mut entities: Vec<Entity> = match (&query.entities, &subtree) {
+                (Some(ids), _) => candidates(&mut ids.iter().map(|id| Entity::from(*id))),
+                (None, Some(subtree)) => candidates(&mut subtree.iter().copied()),
+                (None, None) => dyn_query
+                    .iter(world)
+                    .map(|raw| raw.entity)
+                    .filter(|entity| is_changed(*entity))
+                    .collect(),
+            };
+            entities.retain(|entity| in_scope(*entity));
+            if paged {
+                if let Some(sort) = &query.sort {
+                    sort.sort(world, type_registry, registry, &mut entities);
+                }
+                total = Some(entities.len());
+                entities = entities
+                    .into_iter()
+                    .skip(query.offset)
+                    .take(query.limit.unwrap_or(usize::MAX))
                     .collect();
-                (raw.entity.to_bits(), components)
+            }
+            Box::new(entities.into_iter().filter_map(|entity| {
+                let entity_ref = world.get_entity(entity)?;
+                let components = fetch_entity(
+                    world,
+                    type_registry,
+                    registry,
+                    &entity_ref,
+                    fetch,
+                    &[],
+                    last_change_tick,
+                )?;
+                Some(components.map(|components| (entity, components)))
+            }))
+        } else {
+            Box::new(
+                dyn_query
+                    .iter(world)
+                    .filter(|raw| in_scope(raw.entity) && is_changed(raw.entity))
+                    .map(|raw| -> Matched<V> {
+                        let components = raw
+                            .items
+                            .iter()
+                            .zip(fetch.iter())
+                            .map(|(fetch_res, short_name)| {
+                                let value = capture_fetch(type_registry, fetch_res, short_name)?;
+                                Ok((short_name.clone(), value))
+                            })
+                            .collect::<serde_json::Result<_>>()?;
+                        Ok((raw.entity, components))
+                    }),
+            )
+        };
+
+        let mut resp = match query.layout {
+            ResponseLayout::Rows => {
+                let matches = matched
+                    .map(|matched| {
+                        let (entity, components) = matched?;
+                        matched_now.insert(entity);
+                        let children = run_children(entity)?;
+                        Ok(QueryMatch(EntityId::from(entity), components, children))
+                    })
+                    .collect::<serde_json::Result<_>>()?;
+                QuerySubResp {
+                    matches,
+                    columns: None,
+                    despawned: Vec::new(),
+                    unmatched: Vec::new(),
+                    total: None,
+                    snapshot: false,
+                    id: query.id.clone(),
+                    stamp: Stamp::default(),
+                }
+            }
+            ResponseLayout::Columns => {
+                let mut columns = QueryColumns {
+                    entities: Vec::new(),
+                    components: fetch
+                        .iter()
+                        .map(|short_name| (short_name.clone(), Vec::new()))
+                        .collect(),
+                    children: Vec::new(),
+                };
+                for matched in matched {
+                    let (entity, components) = matched?;
+                    matched_now.insert(entity);
+                    if query.children.is_some() {
+                        columns.children.push(run_children(entity)?);
+                    }
+                    columns.entities.push(EntityId::from(entity));
+                    for (short_name, value) in components {
+                        columns.components.get_mut(&short_name).unwrap().push(value);
+                    }
+                }
+                QuerySubResp {
+                    matches: Vec::new(),
+                    columns: Some(columns),
+                    despawned: Vec::new(),
+                    unmatched: Vec::new(),
+                    total: None,
+                    snapshot: false,
+                    id: query.id.clone(),
+                    stamp: Stamp::default(),
+                }
+            }
+        };
+
+        // Snapshots hold every match, delta runs only the changed ones so the others are
+        // checked again without the `Changed` filters. Watched entities stay tracked so
+        // their despawn is still reported.
+        let still_matches = |entity: Entity| {
+            let Some(entity_ref) = world.get_entity(entity) else {
+                return false;
+            };
+            fetch
+                .iter()
+                .all(|short_name| entity_ref.contains_id(registry.short_name(short_name)))
+                && filters.iter().all(|filter| {
+                    filter.matches_entity(world, registry, &entity_ref, last_change_tick)
+                })
+                && in_scope(entity)
+        };
+        let watched = |entity: Entity| {
+            query
+                .entities
+                .as_ref()
+                .map_or(false, |ids| ids.contains(&EntityId::from(entity)))
+        };
+        let mut unmatched = Vec::new();
+        sent.retain(|entity| {
+            let keep = matched_now.contains(entity)
+                || watched(*entity)
+                || (!snapshot && still_matches(*entity));
+            if !keep {
+                unmatched.push(EntityId::from(*entity));
+            }
+            keep
+        });
+        sent.extend(matched_now);
+
+        resp.despawned = despawned;
+        resp.unmatched = unmatched;
+        resp.total = total;
+        resp.snapshot = snapshot;
+        resp.stamp = Stamp::new(world, last_change_tick, *seq);
+        let matches = resp
+            .columns
+            .as_ref()
+            .map_or(resp.matches.len(), |columns| columns.entities.len());
+        span.record("entities", matches);
+        stats.record_run(matches, resp.stamp.timestamp_ms, started.elapsed());
+        Ok(resp)
+    }
+
+    pub fn subscription_infos(&self) -> Vec<SubscriptionInfo> {
+        self.queries
+            .read()
+            .unwrap()
+            .values()
+            .map(|sub| SubscriptionInfo {
+                connection: sub.connection,
+                req: sub.req.clone(),
+                stats: sub.stats.snapshot(),
             })
-            .collect();
-        QuerySubResp { matches }
+            .collect()
+    }
+}
+
+fn build_query(world: &World, fetch: &[ShortName], filter: &[ShortNameFilter]) -> DynamicQuery {
+    let registry = world.get_resource::<ComponentIdRegistry>().unwrap();
+    let component_fetches = fetch
+        .iter()
+        .map(|short_name| FetchKind::Ref(registry.short_name(short_name)))
+        .collect();
+    let filters = filter
+        .iter()
+        .map(|filter| filter.resolve_components(registry))
+        .collect();
+    DynamicQuery::new(world, component_fetches, filters).unwrap()
+}
+
+/// Calls `f` on every item on the `ComputeTaskPool`, returning the results in order. The
+/// calling thread waits for them, but the work is spread over every core.
+fn par_map<I: Send, T: Send + 'static>(items: Vec<I>, f: impl Fn(I) -> T + Sync) -> Vec<T> {
+    if items.len() < 2 {
+        return items.into_iter().map(f).collect();
+    }
+    let f = &f;
+    ComputeTaskPool::init(TaskPool::default).scope(|scope| {
+        for item in items {
+            scope.spawn(async move { f(item) });
+        }
+    })
+}
+
+fn without_changed(filter: &[ShortNameFilter]) -> Vec<ShortNameFilter> {
+    filter
+        .iter()
+        .filter(|filter| !matches!(filter, ShortNameFilter::Changed(_)))
+        .cloned()
+        .collect()
+}
+
+/// Looks up a short name coming from a client
+pub(crate) fn registered(world: &World, short_name: &str) -> Result<ComponentId, RequestError> {
+    world
+        .get_resource::<ComponentIdRegistry>()
+        .and_then(|registry| registry.try_short_name(short_name))
+        .ok_or_else(|| RequestError::UnknownComponent(short_name.into()))
+}
+
+/// Checks the short names of a query before it gets built, fetched components are also
+/// serialized and have to reflect `Serialize`
+pub(crate) fn validate_query(
+    world: &World,
+    fetch: &[ShortName],
+    filter: &[ShortNameFilter],
+) -> Result<(), RequestError> {
+    for filter in filter {
+        registered(world, filter.short_name())?;
+    }
+    for short_name in fetch {
+        registered(world, short_name)?;
+        let type_registry = world.resource::<AppTypeRegistry>().read();
+        let serializable = type_registry
+            .get_with_short_name(short_name)
+            .and_then(|registration| registration.data::<ReflectSerialize>())
+            .is_some();
+        if !serializable {
+            return Err(RequestError::NotSerializable(short_name.clone()));
+        }
+    }
+    Ok(())
+}
+
+fn capture_fetch<'w, V: ComponentValue<'w>>(
+    type_registry: &'w TypeRegistry,
+    fetch_res: &FetchResult<'w>,
+    short_name: &ShortName,
+) -> serde_json::Result<V> {
+    let FetchResult::Ref(ptr) = fetch_res else {
+        unimplemented!();
+    };
+    capture_ptr(type_registry, *ptr, short_name)
+}
+
+fn reflect_ptr<'a>(
+    type_registry: &TypeRegistry,
+    ptr: Ptr<'a>,
+    short_name: &str,
+) -> &'a dyn Reflect {
+    let type_id = type_registry
+        .get_with_short_name(short_name)
+        .unwrap()
+        .type_id();
+    let reflect = type_registry
+        .get_type_data::<ReflectFromPtr>(type_id)
+        .unwrap();
+
+    // SAFETY:
+    // `val` is a pointer to value of the type that the `ReflectFromPtr` was constructed for,
+    // because the mapping from `ComponentId -> TypeId` is immutable and `ReflectFromPtr` is checked to be
+    // for the type of the `WorldBase`'s type id.
+    unsafe { reflect.as_reflect_ptr(ptr) }
+}
+
+fn capture_ptr<'w, V: ComponentValue<'w>>(
+    type_registry: &'w TypeRegistry,
+    ptr: Ptr<'w>,
+    short_name: &ShortName,
+) -> serde_json::Result<V> {
+    V::capture(type_registry, reflect_ptr(type_registry, ptr, short_name))
+}
+
+/// How a fetched component is captured into a response
+pub trait ComponentValue<'w>: Sized {
+    fn capture(
+        type_registry: &'w TypeRegistry,
+        reflect: &'w dyn Reflect,
+    ) -> serde_json::Result<Self>;
+}
+
+/// Serialized right away through the component's `ReflectSerialize`
+impl<'w> ComponentValue<'w> for Box<RawValue> {
+    fn capture(
+        type_registry: &'w TypeRegistry,
+        reflect: &'w dyn Reflect,
+    ) -> serde_json::Result<Self> {
+        serde_json::value::to_raw_value(&Streamed::capture(type_registry, reflect)?)
+    }
+}
+
+/// Component borrowed from the world, serialized only when the whole response is written
+pub struct Streamed<'w> {
+    reflect: &'w dyn Reflect,
+    type_registry: &'w TypeRegistry,
+}
+
+impl<'w> ComponentValue<'w> for Streamed<'w> {
+    fn capture(
+        type_registry: &'w TypeRegistry,
+        reflect: &'w dyn Reflect,
+    ) -> serde_json::Result<Self> {
+        Ok(Streamed {
+            reflect,
+            type_registry,
+        })
+    }
+}
+
+/// Goes through the component's own `Serialize` impl, so the json is the same as serde
+/// would write for the concrete type
+impl Serialize for Streamed<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Checked on subscribe by `QuerySubReq::validate`
+        let Some(reflect_serialize) = self
+            .type_registry
+            .get_type_data::<ReflectSerialize>(self.reflect.type_id())
+        else {
+            return Err(serde::ser::Error::custom(format_args!(
+                "{} doesn't reflect Serialize",
+                self.reflect.type_name()
+            )));
+        };
+        reflect_serialize
+            .get_serializable(self.reflect)
+            .borrow()
+            .serialize(serializer)
+    }
+}
+
+/// Filters and fetches a single entity directly instead of through a `DynamicQuery`.
+/// Returns `None` if the entity is filtered out or lacks one of the fetched components.
+fn fetch_entity<'w, V: ComponentValue<'w>>(
+    world: &'w World,
+    type_registry: &'w TypeRegistry,
+    registry: &ComponentIdRegistry,
+    entity: &EntityRef<'w>,
+    fetch: &[ShortName],
+    filter: &[ShortNameFilter],
+    last_change_tick: u32,
+) -> Option<serde_json::Result<HashMap<ShortName, V>>> {
+    if !filter
+        .iter()
+        .all(|filter| filter.matches_entity(world, registry, entity, last_change_tick))
+    {
+        return None;
     }
+    let ptrs = fetch
+        .iter()
+        .map(|short_name| {
+            Some((
+                short_name,
+                entity.get_by_id(registry.short_name(short_name))?,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(
+        ptrs.into_iter()
+            .map(|(short_name, ptr)| {
+                Ok((
+                    short_name.clone(),
+                    capture_ptr(type_registry, ptr, short_name)?,
+                ))
+            })
+            .collect(),
+    )
 }
 
 pub trait ToJson {
@@ -130,7 +1033,62 @@ impl<T: erased_serde::Serialize> ToJson for T {
 pub type JsonString = String;
 pub type QueryId = String;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Stamped on every response so results can be lined up by frame across subscriptions
+/// and gaps in a subscription's stream detected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Stamp {
+    /// Bevy `FrameCount` when the response was produced
+    pub frame: u32,
+    /// Wall-clock time in milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    /// Covers changes after `since_tick` up to and including `tick`
+    pub since_tick: u32,
+    pub tick: u32,
+    /// Per-subscription sequence number, starting at 1 and incremented for every response
+    pub seq: u64,
+}
+
+impl Stamp {
+    pub fn new(world: &World, since_tick: u32, seq: u64) -> Self {
+        Stamp {
+            frame: world
+                .get_resource::<FrameCount>()
+                .map_or(0, |frame_count| frame_count.0),
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            since_tick,
+            tick: world.read_change_tick(),
+            seq,
+        }
+    }
+}
+
+/// Entity as seen by clients. The generation is kept apart from the index so a client can
+/// tell a recycled index from the entity that previously held it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntityId {
+    pub index: u32,
+    pub generation: u32,
+}
+
+impl From<Entity> for EntityId {
+    fn from(entity: Entity) -> Self {
+        EntityId {
+            index: entity.index(),
+            generation: entity.generation(),
+        }
+    }
+}
+
+impl From<EntityId> for Entity {
+    fn from(id: EntityId) -> Self {
+        Entity::from_bits((id.generation as u64) << 32 | id.index as u64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ShortNameFilter {
     With(ShortName),
     Without(ShortName),
@@ -139,6 +1097,14 @@ pub enum ShortNameFilter {
 }
 
 impl ShortNameFilter {
+    pub fn short_name(&self) -> &ShortName {
+        match self {
+            ShortNameFilter::With(s)
+            | ShortNameFilter::Without(s)
+            | ShortNameFilter::Changed(s) => s,
+        }
+    }
+
     pub fn resolve_components(&self, registry: &ComponentIdRegistry) -> FilterKind {
         match self {
             ShortNameFilter::With(s) => FilterKind::With(registry.short_name(s)),
@@ -146,6 +1112,26 @@ impl ShortNameFilter {
             ShortNameFilter::Changed(s) => FilterKind::Changed(registry.short_name(s)),
         }
     }
+
+    /// Checks one entity directly. Unlike the resolved `FilterKind`, which compares against
+    /// the world's last change tick, `Changed` compares against `last_change_tick`.
+    pub fn matches_entity(
+        &self,
+        world: &World,
+        registry: &ComponentIdRegistry,
+        entity: &EntityRef,
+        last_change_tick: u32,
+    ) -> bool {
+        match self {
+            ShortNameFilter::With(s) => entity.contains_id(registry.short_name(s)),
+            ShortNameFilter::Without(s) => !entity.contains_id(registry.short_name(s)),
+            ShortNameFilter::Changed(s) => registry
+                .change_ticks(entity, registry.short_name(s))
+                .map_or(false, |ticks| {
+                    ticks.is_changed(last_change_tick, world.read_change_tick())
+                }),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -154,21 +1140,305 @@ pub struct ResourceSubReq {
     pub only_changed: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct QuerySubReq {
     pub id: QueryId,
     pub fetch: Vec<ShortName>,
     pub filter: Vec<ShortNameFilter>,
+    #[serde(default)]
+    pub layout: ResponseLayout,
+    /// Only match these entities, fetching each directly instead of iterating the query
+    #[serde(default)]
+    pub entities: Option<Vec<EntityId>>,
+    /// Only match descendants of this entity
+    #[serde(default)]
+    pub root: Option<EntityId>,
+    /// Only match entities whose transform lies inside this region
+    #[serde(default)]
+    pub region: Option<Region>,
+    /// Only run every `interval` frames instead of every frame
+    #[serde(default)]
+    pub interval: Option<u32>,
+    /// Query run against the children of every match, nested under it in the response
+    #[serde(default)]
+    pub children: Option<Box<ChildQuery>>,
+    #[serde(default)]
+    pub sort: Option<SortBy>,
+    /// Maximum number of matches returned, applied after sorting and `offset`
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+impl QuerySubReq {
+    /// Fails on the first short name that isn't registered, so a typo is reported back
+    /// instead of panicking once the query gets built
+    pub fn validate(&self, world: &World) -> Result<(), RequestError> {
+        validate_query(world, &self.fetch, &self.filter)?;
+        if let Some(sort) = &self.sort {
+            registered(world, field::component(&sort.field))?;
+        }
+        match &self.children {
+            Some(children) => children.validate(world),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads a request from a uri query string for clients that can't send a body, e.g.
+    /// `id=units&fetch=Location,Health&without=Dead&changed=Health&interval=10`.
+    /// Keys and values are percent decoded, so short names may contain e.g. `%3A`.
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        let mut req = QuerySubReq::default();
+        let names = |value: &str| -> Vec<ShortName> {
+            value
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(ShortName::from)
+                .collect()
+        };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let invalid = |e: std::num::ParseIntError| format!("invalid {key} '{value}': {e}");
+            match &*key {
+                "id" => req.id = value.to_string(),
+                "fetch" => req.fetch.extend(names(&value)),
+                "with" => req
+                    .filter
+                    .extend(names(&value).into_iter().map(ShortNameFilter::With)),
+                "without" => req
+                    .filter
+                    .extend(names(&value).into_iter().map(ShortNameFilter::Without)),
+                "changed" => req
+                    .filter
+                    .extend(names(&value).into_iter().map(ShortNameFilter::Changed)),
+                "interval" => req.interval = Some(value.parse().map_err(invalid)?),
+                "limit" => req.limit = Some(value.parse().map_err(invalid)?),
+                "offset" => req.offset = value.parse().map_err(invalid)?,
+                _ => {}
+            }
+        }
+        Ok(req)
+    }
+}
+
+/// Shape of the matches in a `QuerySubResp`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResponseLayout {
+    /// One `(entity, {short_name: value})` entry per match, returned in `matches`
+    #[default]
+    Rows,
+    /// One entity array plus one value array per fetched component, returned in `columns`
+    Columns,
+}
+
+/// Holds serialized components by default, or components borrowed from the world when
+/// written straight into a buffer by `EcsSubApi::write_query`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QuerySubResp<V = Box<RawValue>> {
+    pub id: QueryId,
+    pub stamp: Stamp,
+    /// Whether this response holds every match rather than only those that changed.
+    /// The first response and the one after a resync are always snapshots.
+    #[serde(default)]
+    pub snapshot: bool,
+    pub matches: Vec<QueryMatch<V>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub columns: Option<QueryColumns<V>>,
+    /// Previously sent entities that have since been despawned, with the exact generation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub despawned: Vec<EntityId>,
+    /// Previously sent entities that are still alive but no longer match, e.g. because a
+    /// fetched component was removed. Their despawn won't be reported.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unmatched: Vec<EntityId>,
+    /// Number of matches before `offset` and `limit` were applied, only set when paging
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+}
+
+impl<V> QuerySubResp<V> {
+    /// Converts every component value, keeping the rest of the response as is
+    pub fn map_values<W>(self, mut f: impl FnMut(V) -> W) -> QuerySubResp<W> {
+        QuerySubResp {
+            id: self.id,
+            stamp: self.stamp,
+            snapshot: self.snapshot,
+            matches: self
+                .matches
+                .into_iter()
+                .map(|QueryMatch(entity, components, children)| {
+                    QueryMatch(
+                        entity,
+                        map_components(components, &mut f),
+                        map_children(children, &mut f),
+                    )
+                })
+                .collect(),
+            columns: self.columns.map(|columns| QueryColumns {
+                entities: columns.entities,
+                components: columns
+                    .components
+                    .into_iter()
+                    .map(|(short_name, values)| {
+                        (short_name, values.into_iter().map(&mut f).collect())
+                    })
+                    .collect(),
+                children: columns
+                    .children
+                    .into_iter()
+                    .map(|children| map_children(children, &mut f))
+                    .collect(),
+            }),
+            despawned: self.despawned,
+            unmatched: self.unmatched,
+            total: self.total,
+        }
+    }
+}
+
+/// A `QuerySubResp` already written as json by `EcsSubApi::write_query`, copied into
+/// `ServerMsg` frames as is so it's never parsed again on the way out
+#[derive(Clone, Debug)]
+pub struct QuerySubJson {
+    pub id: QueryId,
+    pub stamp: Stamp,
+    pub json: Arc<str>,
+}
+
+impl QuerySubJson {
+    /// `json` has to be valid json, only its encoding is checked
+    pub fn new(id: QueryId, stamp: Stamp, json: &[u8]) -> serde_json::Result<Self> {
+        let json = std::str::from_utf8(json).map_err(serde::de::Error::custom)?;
+        Ok(QuerySubJson {
+            id,
+            stamp,
+            json: Arc::from(json),
+        })
+    }
+
+    pub fn parse(&self) -> serde_json::Result<QuerySubResp> {
+        serde_json::from_str(&self.json)
+    }
+}
+
+/// Goes through a `RawValue` so any serializer keeps the same format, `ServerMsg::to_frame`
+/// skips this and writes the json directly
+impl Serialize for QuerySubJson {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let json: &RawValue =
+            serde_json::from_str(&self.json).map_err(serde::ser::Error::custom)?;
+        json.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QuerySubJson {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Header {
+            id: QueryId,
+            stamp: Stamp,
+        }
+        let json = Box::<RawValue>::deserialize(deserializer)?;
+        let Header { id, stamp } =
+            serde_json::from_str(json.get()).map_err(serde::de::Error::custom)?;
+        Ok(QuerySubJson {
+            id,
+            stamp,
+            json: Arc::from(json.get()),
+        })
+    }
+}
+
+impl QuerySubResp {
+    /// Bytes of serialized component values in the response
+    pub fn payload_len(&self) -> usize {
+        fn child_len(child: &ChildMatch) -> usize {
+            child
+                .components
+                .values()
+                .map(|value| value.get().len())
+                .sum::<usize>()
+                + child.children.iter().map(child_len).sum::<usize>()
+        }
+        let rows: usize = self
+            .matches
+            .iter()
+            .map(|QueryMatch(_, components, children)| {
+                components
+                    .values()
+                    .map(|value| value.get().len())
+                    .sum::<usize>()
+                    + children.iter().map(child_len).sum::<usize>()
+            })
+            .sum();
+        let columns: usize = self
+            .columns
+            .iter()
+            .map(|columns| {
+                columns
+                    .components
+                    .values()
+                    .flatten()
+                    .map(|value| value.get().len())
+                    .sum::<usize>()
+                    + columns
+                        .children
+                        .iter()
+                        .flatten()
+                        .map(child_len)
+                        .sum::<usize>()
+            })
+            .sum();
+        rows + columns
+    }
+}
+
+pub(crate) fn map_components<V, W>(
+    components: HashMap<ShortName, V>,
+    f: &mut impl FnMut(V) -> W,
+) -> HashMap<ShortName, W> {
+    components
+        .into_iter()
+        .map(|(short_name, value)| (short_name, f(value)))
+        .collect()
+}
+
+fn map_children<V, W>(
+    children: Vec<ChildMatch<V>>,
+    f: &mut impl FnMut(V) -> W,
+) -> Vec<ChildMatch<W>> {
+    children
+        .into_iter()
+        .map(|child| child.map_values(f))
+        .collect()
 }
 
+/// One `[entity, {short_name: value}]` match, followed by the matches of the child query
+/// among its children if there are any
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct QuerySubResp {
-    pub matches: Vec<(u64, HashMap<ShortName, Box<RawValue>>)>,
+pub struct QueryMatch<V = Box<RawValue>>(
+    pub EntityId,
+    pub HashMap<ShortName, V>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")] pub Vec<ChildMatch<V>>,
+);
+
+/// Struct-of-arrays form of the matches, `components[name][i]` belongs to `entities[i]`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct QueryColumns<V = Box<RawValue>> {
+    pub entities: Vec<EntityId>,
+    pub components: HashMap<ShortName, Vec<V>>,
+    /// Child matches of `entities[i]` at `children[i]`, only set when the query has a
+    /// child query
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Vec<ChildMatch<V>>>,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::aggregate::AggregateOp;
+    use crate::field::FieldValue;
+    use crate::lifecycle::LifecyclePlugin;
     use std::time::Duration;
 
     use bevy::{app::ScheduleRunnerSettings, prelude::*};
@@ -215,6 +1485,7 @@ mod test {
                 id: "Both".into(),
                 fetch: vec!["Location".into()],
                 filter: vec![],
+                ..default()
             },
             &world,
         );
@@ -228,9 +1499,14 @@ mod test {
 
         let raw_value = serde_json::value::to_raw_value(&Location { city: "NYC".into() }).unwrap();
         let expected = QuerySubResp {
+            id: "Both".into(),
+            stamp: resp.stamp,
             matches: vec![
-                (
-                    0,
+                QueryMatch(
+                    EntityId {
+                        index: 0,
+                        generation: 0,
+                    },
                     HashMap::from_iter([(
                         "Location".to_string(),
                         serde_json::value::to_raw_value(&Location {
@@ -238,24 +1514,1038 @@ mod test {
                         })
                         .unwrap(),
                     )]),
+                    vec![],
                 ),
-                (
-                    1,
+                QueryMatch(
+                    EntityId {
+                        index: 1,
+                        generation: 0,
+                    },
                     HashMap::from_iter([(
                         "Location".to_string(),
                         serde_json::value::to_raw_value(&Location { city: "NYC".into() }).unwrap(),
                     )]),
+                    vec![],
                 ),
-                (
-                    2,
+                QueryMatch(
+                    EntityId {
+                        index: 2,
+                        generation: 0,
+                    },
                     HashMap::from_iter([(
                         "Location".to_string(),
                         serde_json::value::to_raw_value(&Location { city: "SLC".into() }).unwrap(),
                     )]),
+                    vec![],
                 ),
             ],
+            columns: None,
+            despawned: vec![],
+            unmatched: vec![],
+            total: None,
+            snapshot: true,
         };
         let expected_json = serde_json::to_string(&expected).unwrap();
         assert_eq!(resp_json, expected_json);
     }
+
+    #[test]
+    fn reports_despawn_of_exact_generation() {
+        let mut world = World::new();
+        world.register::<Location>();
+        let entity = world.spawn(Location { city: "NYC".into() }).id();
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "Locations".into(),
+                fetch: vec!["Location".into()],
+                ..default()
+            },
+            &world,
+        );
+        let resp = api.run_query(&world, &"Locations".to_string());
+        assert_eq!(resp.matches.len(), 1);
+        assert!(resp.despawned.is_empty());
+
+        world.despawn(entity);
+        let recycled = world.spawn(Location { city: "SLC".into() }).id();
+        assert_eq!(recycled.index(), entity.index());
+
+        let resp = api.run_query(&world, &"Locations".to_string());
+        assert_eq!(resp.despawned, vec![EntityId::from(entity)]);
+        assert_eq!(resp.matches[0].0, EntityId::from(recycled));
+    }
+
+    #[test]
+    fn watches_entities_through_filters() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.register::<Health>();
+        let watched = world
+            .spawn((Location { city: "NYC".into() }, Health { health: 50 }))
+            .id();
+        let filtered = world.spawn(Location { city: "SLC".into() }).id();
+        world.spawn((Location { city: "LA".into() }, Health { health: 40 }));
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "Selected".into(),
+                fetch: vec!["Location".into()],
+                filter: vec![ShortNameFilter::With("Health".into())],
+                entities: Some(vec![EntityId::from(watched), EntityId::from(filtered)]),
+                ..default()
+            },
+            &world,
+        );
+        let resp = api.run_query(&world, &"Selected".to_string());
+        let matched: Vec<_> = resp.matches.iter().map(|m| m.0).collect();
+        assert_eq!(matched, [EntityId::from(watched)]);
+
+        // Watched entities are reported on despawn even if they never matched
+        world.despawn(filtered);
+        let resp = api.run_query(&world, &"Selected".to_string());
+        assert_eq!(resp.despawned, [EntityId::from(filtered)]);
+        assert_eq!(resp.matches.len(), 1);
+    }
+
+    #[test]
+    fn tests_points_against_regions() {
+        let aabb = Region::Aabb {
+            min: [-1.0, -1.0, -1.0],
+            max: [1.0, 2.0, 1.0],
+        };
+        assert!(aabb.contains(Vec3::new(1.0, 2.0, 0.0)));
+        assert!(!aabb.contains(Vec3::new(0.0, 2.5, 0.0)));
+        let sphere = Region::Sphere {
+            center: [10.0, 0.0, 0.0],
+            radius: 2.0,
+        };
+        assert!(sphere.contains(Vec3::new(12.0, 0.0, 0.0)));
+        assert!(!sphere.contains(Vec3::new(11.5, 1.5, 0.0)));
+    }
+
+    #[test]
+    fn filters_by_region_and_moves_it() {
+        let mut world = World::new();
+        world.register::<Location>();
+        let near = world
+            .spawn((
+                Location { city: "NYC".into() },
+                Transform::from_xyz(0.5, 0.0, 0.0),
+            ))
+            .id();
+        let far = world
+            .spawn((
+                Location { city: "SLC".into() },
+                Transform::from_xyz(10.0, 0.0, 0.0),
+            ))
+            .id();
+        world.spawn(Location { city: "LA".into() });
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "Nearby".into(),
+                fetch: vec!["Location".into()],
+                region: Some(Region::Sphere {
+                    center: [0.0, 0.0, 0.0],
+                    radius: 1.0,
+                }),
+                ..default()
+            },
+            &world,
+        );
+        let matched = |api: &EcsSubApi| -> Vec<EntityId> {
+            let resp = api.run_query(&world, &"Nearby".to_string());
+            resp.matches.iter().map(|m| m.0).collect()
+        };
+        assert_eq!(matched(&api), [EntityId::from(near)]);
+
+        let id = "Nearby".to_string();
+        let region = Region::Aabb {
+            min: [9.0, -1.0, -1.0],
+            max: [11.0, 1.0, 1.0],
+        };
+        api.set_region(None, &id, Some(region)).unwrap();
+        assert_eq!(matched(&api), [EntityId::from(far)]);
+        api.set_region(None, &id, None).unwrap();
+        assert_eq!(matched(&api).len(), 3);
+        assert_eq!(
+            api.set_region(Some(1), &id, None),
+            Err(RequestError::UnknownSubscription(id))
+        );
+    }
+
+    #[test]
+    fn sends_entities_entering_a_moved_region() {
+        let mut world = World::new();
+        world.register::<Health>();
+        let near = world
+            .spawn((Health { health: 50 }, Transform::from_xyz(0.5, 0.0, 0.0)))
+            .id();
+        let far = world
+            .spawn((Health { health: 40 }, Transform::from_xyz(10.0, 0.0, 0.0)))
+            .id();
+
+        let api = EcsSubApi::default();
+        let id = "Nearby".to_string();
+        api.subscribe_components(
+            QuerySubReq {
+                id: id.clone(),
+                fetch: vec!["Health".into()],
+                filter: vec![ShortNameFilter::Changed("Health".into())],
+                region: Some(Region::Sphere {
+                    center: [0.0, 0.0, 0.0],
+                    radius: 1.0,
+                }),
+                ..default()
+            },
+            &world,
+        );
+        let run = |world: &mut World| {
+            let resp = api.run_query(world, &id);
+            world.clear_trackers();
+            let matched: Vec<EntityId> = resp.matches.iter().map(|m| m.0).collect();
+            (resp.snapshot, matched, resp.unmatched)
+        };
+        assert_eq!(run(&mut world), (true, vec![near.into()], vec![]));
+        assert_eq!(run(&mut world), (false, vec![], vec![]));
+
+        // Neither entity changed, the one entering the region is still sent
+        let region = Region::Sphere {
+            center: [10.0, 0.0, 0.0],
+            radius: 1.0,
+        };
+        api.set_region(None, &id, Some(region)).unwrap();
+        assert_eq!(run(&mut world), (true, vec![far.into()], vec![near.into()]));
+        assert_eq!(run(&mut world), (false, vec![], vec![]));
+
+        // Setting the same region again doesn't resend anything
+        api.set_region(None, &id, Some(region)).unwrap();
+        assert_eq!(run(&mut world), (false, vec![], vec![]));
+    }
+
+    #[test]
+    fn resends_snapshot_after_resync() {
+        let mut world = World::new();
+        world.register::<Health>();
+        let changed = world.spawn(Health { health: 50 }).id();
+        world.spawn(Health { health: 40 });
+
+        let api = EcsSubApi::default();
+        let id = "Health".to_string();
+        api.subscribe_components(
+            QuerySubReq {
+                id: id.clone(),
+                fetch: vec!["Health".into()],
+                filter: vec![ShortNameFilter::Changed("Health".into())],
+                ..default()
+            },
+            &world,
+        );
+        let run = |world: &mut World| {
+            let resp = api.run_query(world, &id);
+            world.clear_trackers();
+            (resp.snapshot, resp.matches.len(), resp.stamp.seq)
+        };
+        assert_eq!(run(&mut world), (true, 2, 1));
+        assert_eq!(run(&mut world), (false, 0, 2));
+
+        world.get_mut::<Health>(changed).unwrap().health = 10;
+        api.resync(None, &id).unwrap();
+        assert_eq!(run(&mut world), (true, 2, 3));
+        assert_eq!(run(&mut world), (false, 0, 4));
+        assert_eq!(
+            api.resync(Some(1), &id),
+            Err(RequestError::UnknownSubscription(id.clone()))
+        );
+    }
+
+    #[test]
+    fn throttles_to_interval_without_missing_changes() {
+        let mut world = World::new();
+        world.register::<Health>();
+        let entity = world.spawn(Health { health: 50 }).id();
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "Health".into(),
+                fetch: vec!["Health".into()],
+                filter: vec![ShortNameFilter::Changed("Health".into())],
+                interval: Some(3),
+                ..default()
+            },
+            &world,
+        );
+        let mut runs = Vec::new();
+        for frame in 0..7 {
+            world.clear_trackers();
+            // Made right after the first run, on a frame that is skipped
+            if frame == 1 {
+                world.get_mut::<Health>(entity).unwrap().health = 10;
+            }
+            let resps = api.run_all_queries(&world);
+            runs.extend(resps.iter().map(|resp| (frame, resp.matches.len())));
+        }
+        assert_eq!(runs, [(0, 1), (3, 1), (6, 0)]);
+    }
+
+    #[test]
+    fn keeps_change_tick_on_modify() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.register::<Health>();
+        let changed = world
+            .spawn((Location { city: "NYC".into() }, Health { health: 50 }))
+            .id();
+        world.spawn((Location { city: "SLC".into() }, Health { health: 40 }));
+
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "Health".into(),
+            fetch: vec!["Health".into(), "Location".into()],
+            filter: vec![ShortNameFilter::Changed("Health".into())],
+            ..default()
+        };
+        api.subscribe_components(query.clone(), &world);
+        assert_eq!(api.run_query(&world, &query.id).matches.len(), 2);
+        world.clear_trackers();
+        world.get_mut::<Health>(changed).unwrap().health = 10;
+
+        // Rebuilt for the smaller fetch, the change made before still comes through
+        let query = api.modify_subscription(
+            QuerySubReq {
+                fetch: vec!["Health".into()],
+                ..query
+            },
+            &world,
+        );
+        let resp = api.run_query(&world, &query.id);
+        assert!(!resp.snapshot);
+        let matched: Vec<_> = resp.matches.iter().map(|m| m.0).collect();
+        assert_eq!(matched, [EntityId::from(changed)]);
+
+        // Newly fetched components have no values on the client yet
+        let query = api.modify_subscription(
+            QuerySubReq {
+                fetch: vec!["Health".into(), "Location".into()],
+                ..query
+            },
+            &world,
+        );
+        let resp = api.run_query(&world, &query.id);
+        assert!(resp.snapshot);
+        assert_eq!(resp.matches.len(), 2);
+    }
+
+    #[test]
+    fn snapshots_after_rescoping_on_modify() {
+        let mut world = World::new();
+        world.register::<Health>();
+        let watched = world.spawn(Health { health: 50 }).id();
+        let added = world.spawn(Health { health: 40 }).id();
+
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "Health".into(),
+            fetch: vec!["Health".into()],
+            filter: vec![ShortNameFilter::Changed("Health".into())],
+            entities: Some(vec![watched.into()]),
+            ..default()
+        };
+        api.subscribe_components(query.clone(), &world);
+        let id = query.id.clone();
+        let run = |world: &mut World| {
+            let resp = api.run_query(world, &id);
+            world.clear_trackers();
+            let matched: Vec<EntityId> = resp.matches.iter().map(|m| m.0).collect();
+            (resp.snapshot, matched)
+        };
+        assert_eq!(run(&mut world), (true, vec![watched.into()]));
+        assert_eq!(run(&mut world), (false, vec![]));
+
+        // Only the interval changed, the delta stream carries on
+        let query = api.modify_subscription(
+            QuerySubReq {
+                interval: Some(1),
+                ..query
+            },
+            &world,
+        );
+        assert_eq!(run(&mut world), (false, vec![]));
+
+        // The newly watched entity never changed, it still reaches the client
+        api.modify_subscription(
+            QuerySubReq {
+                entities: Some(vec![watched.into(), added.into()]),
+                ..query
+            },
+            &world,
+        );
+        assert_eq!(run(&mut world), (true, vec![watched.into(), added.into()]));
+        assert_eq!(run(&mut world), (false, vec![]));
+    }
+
+    #[test]
+    fn forgets_entities_that_no_longer_match() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.register::<Health>();
+        let kept = world
+            .spawn((Location { city: "NYC".into() }, Health { health: 50 }))
+            .id();
+        let healed = world
+            .spawn((Location { city: "SLC".into() }, Health { health: 40 }))
+            .id();
+
+        let api = EcsSubApi::default();
+        for (id, filter) in [
+            ("Health", vec![]),
+            ("Changed", vec![ShortNameFilter::Changed("Health".into())]),
+        ] {
+            api.subscribe_components(
+                QuerySubReq {
+                    id: id.into(),
+                    fetch: vec!["Location".into()],
+                    filter: [vec![ShortNameFilter::With("Health".into())], filter].concat(),
+                    ..default()
+                },
+                &world,
+            );
+            assert_eq!(api.run_query(&world, &id.to_string()).matches.len(), 2);
+        }
+        world.clear_trackers();
+
+        // Unchanged entities don't show up in delta runs but still match
+        let resp = api.run_query(&world, &"Changed".to_string());
+        assert!(resp.matches.is_empty());
+        assert!(resp.unmatched.is_empty());
+
+        world.entity_mut(healed).remove::<Health>();
+        for id in ["Health", "Changed"] {
+            let resp = api.run_query(&world, &id.to_string());
+            assert_eq!(resp.unmatched, vec![EntityId::from(healed)], "{id}");
+            assert_eq!(resp.matches.len(), (id == "Health") as usize, "{id}");
+        }
+
+        // Already reported, its despawn is no concern of the client anymore
+        world.despawn(healed);
+        world.despawn(kept);
+        for id in ["Health", "Changed"] {
+            let resp = api.run_query(&world, &id.to_string());
+            assert!(resp.unmatched.is_empty(), "{id}");
+            assert_eq!(resp.despawned, vec![EntityId::from(kept)], "{id}");
+        }
+    }
+
+    #[test]
+    fn writes_query_into_reused_buffer() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.spawn(Location { city: "NYC".into() });
+
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "Locations".into(),
+            fetch: vec!["Location".into()],
+            ..default()
+        };
+        api.subscribe_components(query.clone(), &world);
+        let direct = api.run_query(&world, &query.id);
+
+        let mut queries = api.queries.write().unwrap();
+        let sub = queries.get_mut(&(None, query.id.clone())).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(api.write_query(&world, sub).unwrap()).unwrap();
+        assert_eq!(
+            written["matches"],
+            serde_json::to_value(&direct.matches).unwrap()
+        );
+        assert_eq!(written["stamp"]["seq"], 2);
+
+        // No longer json than the last run, it has to land in the same allocation
+        let (ptr, capacity) = (sub.buf.as_ptr(), sub.buf.capacity());
+        sub.needs_snapshot = true;
+        let rewritten: serde_json::Value =
+            serde_json::from_slice(api.write_query(&world, sub).unwrap()).unwrap();
+        assert_eq!(rewritten["matches"], written["matches"]);
+        assert_eq!((sub.buf.as_ptr(), sub.buf.capacity()), (ptr, capacity));
+    }
+
+    #[test]
+    fn passes_written_queries_through_server_messages() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.spawn(Location { city: "NYC".into() });
+
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "Locations".into(),
+            fetch: vec!["Location".into()],
+            ..default()
+        };
+        api.subscribe_components(query, &world);
+        let mut written = Vec::new();
+        api.write_all_queries(&world, |_, resp| written.push(resp));
+        let [resp] = &written[..] else {
+            panic!("expected one response, got {written:?}");
+        };
+        assert_eq!((resp.id.as_str(), resp.stamp.seq), ("Locations", 1));
+
+        let msg = crate::protocol::ServerMsg::QuerySubResp(resp.clone());
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, format!("{{\"QuerySubResp\":{}}}", resp.json));
+        let crate::protocol::ServerMsg::QuerySubResp(read) = serde_json::from_str(&json).unwrap()
+        else {
+            panic!("expected a query response");
+        };
+        assert_eq!((read.id, read.stamp), (resp.id.clone(), resp.stamp));
+        assert_eq!(read.parse().unwrap().matches.len(), 1);
+    }
+
+    #[test]
+    fn writes_every_due_query_across_the_task_pool() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.spawn(Location { city: "NYC".into() });
+
+        let api = EcsSubApi::default();
+        for i in 0..8 {
+            let query = QuerySubReq {
+                id: format!("Locations{i}"),
+                fetch: vec!["Location".into()],
+                // Not due again on the second frame
+                interval: (i == 7).then_some(2),
+                ..default()
+            };
+            api.subscribe_components(query, &world);
+        }
+        api.write_all_queries(&world, |_, _| {});
+        let mut written = Vec::new();
+        api.write_all_queries(&world, |sub, resp| {
+            assert_eq!(sub.req.id, resp.id);
+            assert_eq!(resp.parse().unwrap().matches.len(), 1);
+            written.push(resp.id);
+        });
+        written.sort();
+        let expected: Vec<_> = (0..7).map(|i| format!("Locations{i}")).collect();
+        assert_eq!(written, expected);
+    }
+
+    #[derive(Debug, Default, Resource, Reflect, serde::Serialize)]
+    #[reflect(Resource, Serialize)]
+    struct Weather {
+        pub raining: bool,
+    }
+
+    #[test]
+    fn queries_once_without_subscribing() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.register::<Health>();
+        world.spawn((Location { city: "NYC".into() }, Health { health: 50 }));
+        let entity = world.spawn(Location { city: "SLC".into() }).id();
+        world.insert_resource(Weather { raining: true });
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Weather>();
+
+        let api = EcsSubApi::default();
+        let resp = api
+            .query_once(
+                QuerySubReq {
+                    id: "Once".into(),
+                    fetch: vec!["Location".into()],
+                    filter: vec![ShortNameFilter::Without("Health".into())],
+                    ..default()
+                },
+                &world,
+            )
+            .unwrap();
+        assert!(api.queries.read().unwrap().is_empty());
+        assert!(resp.snapshot);
+        assert_eq!(resp.matches.len(), 1);
+        assert_eq!(resp.matches[0].0, EntityId::from(entity));
+
+        let components = inspect::read_entity(&world, EntityId::from(entity)).unwrap();
+        assert_eq!(components.len(), 1);
+        assert_eq!(components["Location"].get(), r#"{"city":"SLC"}"#);
+        assert_eq!(inspect::component_names(&world), ["Health", "Location"]);
+        assert_eq!(inspect::resource_names(&world), ["Weather"]);
+        assert_eq!(
+            inspect::read_resource(&world, "Weather").unwrap().get(),
+            r#"{"raining":true}"#
+        );
+        assert_eq!(
+            inspect::read_resource(&world, "Wether").unwrap_err(),
+            RequestError::UnknownResource("Wether".into())
+        );
+        world.despawn(entity);
+        assert_eq!(
+            inspect::read_entity(&world, EntityId::from(entity)).unwrap_err(),
+            RequestError::UnknownEntity(EntityId::from(entity))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_short_names() {
+        let mut world = World::new();
+        world.register::<Location>();
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "Typo".into(),
+            fetch: vec!["Location".into()],
+            ..default()
+        };
+        let typo = |query: QuerySubReq| api.query_once(query, &world).unwrap_err();
+        assert_eq!(
+            typo(QuerySubReq {
+                fetch: vec!["Locaton".into()],
+                ..query.clone()
+            }),
+            RequestError::UnknownComponent("Locaton".into())
+        );
+        assert_eq!(
+            typo(QuerySubReq {
+                filter: vec![ShortNameFilter::Changed("Helth".into())],
+                ..query.clone()
+            }),
+            RequestError::UnknownComponent("Helth".into())
+        );
+        assert_eq!(
+            typo(QuerySubReq {
+                children: Some(Box::new(ChildQuery {
+                    fetch: vec!["Item".into()],
+                    ..default()
+                })),
+                ..query.clone()
+            }),
+            RequestError::UnknownComponent("Item".into())
+        );
+        assert!(api.subscribe_components_for(Some(1), query, &world).is_ok());
+        assert_eq!(api.queries.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reads_request_from_uri_query() {
+        let req = QuerySubReq::from_query(
+            "id=units&fetch=Location,Health&without=Dead&changed=Health&interval=10",
+        )
+        .unwrap();
+        assert_eq!(req.id, "units");
+        assert_eq!(req.fetch, ["Location", "Health"]);
+        assert_eq!(
+            req.filter,
+            [
+                ShortNameFilter::Without("Dead".into()),
+                ShortNameFilter::Changed("Health".into())
+            ]
+        );
+        assert_eq!(req.interval, Some(10));
+        assert!(QuerySubReq::from_query("limit=ten").is_err());
+        assert!(QuerySubReq::from_query("interval=5000000000").is_err());
+
+        let req =
+            QuerySubReq::from_query("id=my%20units&fetch=Location%2CHealth&with=a%3Ab").unwrap();
+        assert_eq!(req.id, "my units");
+        assert_eq!(req.fetch, ["Location", "Health"]);
+        assert_eq!(req.filter, [ShortNameFilter::With("a:b".into())]);
+    }
+
+    #[test]
+    fn emits_lifecycle_events_on_disconnect() {
+        let mut app = App::new();
+        app.add_plugin(LifecyclePlugin);
+        app.world.register::<Location>();
+        let api = EcsSubApi::default();
+        api.connect(7);
+        api.subscribe_components_for(
+            Some(7),
+            QuerySubReq {
+                id: "Locations".into(),
+                fetch: vec!["Location".into()],
+                ..default()
+            },
+            &app.world,
+        )
+        .unwrap();
+        api.disconnect(7);
+        assert!(api.queries.read().unwrap().is_empty());
+        app.insert_resource(api);
+        app.update();
+
+        // Read both buffers, the flush and the events' own update share a stage
+        fn read<T: bevy::ecs::event::Event>(app: &App) -> Vec<&T> {
+            let events = app.world.resource::<Events<T>>();
+            events.get_reader().iter(events).collect()
+        }
+        assert_eq!(read::<SubscriptionAdded>(&app).len(), 1);
+        let removed = read::<SubscriptionRemoved>(&app);
+        assert_eq!(removed[0].connection, Some(7));
+        assert_eq!(removed[0].id, "Locations");
+        assert_eq!(read::<ClientDisconnected>(&app).len(), 1);
+    }
+
+    #[test]
+    fn keys_subscriptions_by_connection() {
+        let mut app = App::new();
+        app.add_plugin(LifecyclePlugin);
+        app.world.register::<Location>();
+        app.world.register_event::<Damage>();
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "query_1".into(),
+            fetch: vec!["Location".into()],
+            ..default()
+        };
+        for connection in [1, 2] {
+            api.subscribe_components_for(Some(connection), query.clone(), &app.world)
+                .unwrap();
+        }
+        assert_eq!(api.queries.read().unwrap().len(), 2);
+
+        // Neither connection can touch the other's subscription
+        api.unsubscribe(Some(1), &query.id).unwrap();
+        let unknown = Err(RequestError::UnknownSubscription(query.id.clone()));
+        assert_eq!(api.unsubscribe(Some(1), &query.id), unknown);
+        assert_eq!(api.resync(Some(3), &query.id), unknown);
+        assert_eq!(api.set_region(None, &query.id, None), unknown);
+        let modified = QuerySubReq {
+            interval: Some(5),
+            ..query.clone()
+        };
+        api.modify_subscription_for(Some(3), modified, &app.world)
+            .unwrap();
+        assert_eq!(
+            api.queries.read().unwrap()[&(Some(2), query.id.clone())]
+                .req
+                .interval,
+            None
+        );
+
+        api.subscribe_components_for(Some(2), query.clone(), &app.world)
+            .unwrap();
+        api.subscribe_aggregate_for(
+            Some(2),
+            AggregateSubReq {
+                id: "PerCity".into(),
+                fetch: vec!["Location".into()],
+                filter: vec![],
+                op: AggregateOp::Count,
+                group_by: None,
+            },
+            &app.world,
+        )
+        .unwrap();
+        api.subscribe_events_for(Some(2), "Damage".into(), &app.world)
+            .unwrap();
+        api.disconnect(2);
+        assert!(api.aggregates.read().unwrap().is_empty());
+        assert!(api.events.read().unwrap().is_empty());
+        assert_eq!(api.queries.read().unwrap().len(), 1);
+
+        // Subscribing again under the same id replaces the old subscription
+        let events = std::mem::take(&mut *api.lifecycle.lock().unwrap());
+        let changes: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                LifecycleEvent::SubscriptionAdded(added) => {
+                    Some(("added", added.req.kind(), added.connection))
+                }
+                LifecycleEvent::SubscriptionRemoved(removed) => {
+                    Some(("removed", removed.kind, removed.connection))
+                }
+                _ => None,
+            })
+            .collect();
+        use SubscriptionKind::{Aggregate, Events, Query};
+        assert_eq!(
+            changes,
+            [
+                ("added", Query, Some(1)),
+                ("added", Query, Some(2)),
+                ("removed", Query, Some(1)),
+                ("added", Query, Some(3)),
+                ("removed", Query, Some(2)),
+                ("added", Query, Some(2)),
+                ("added", Aggregate, Some(2)),
+                ("added", Events, Some(2)),
+                ("removed", Query, Some(2)),
+                ("removed", Aggregate, Some(2)),
+                ("removed", Events, Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn nests_child_matches_under_parent() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.register::<Health>();
+        let child = world.spawn(Health { health: 10 }).id();
+        let parent = world
+            .spawn(Location { city: "NYC".into() })
+            .push_children(&[child])
+            .id();
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "Inventory".into(),
+                fetch: vec!["Location".into()],
+                children: Some(Box::new(ChildQuery {
+                    fetch: vec!["Health".into()],
+                    ..default()
+                })),
+                ..default()
+            },
+            &world,
+        );
+        let resp = api.run_query(&world, &"Inventory".to_string());
+        assert_eq!(resp.matches.len(), 1);
+        let QueryMatch(entity, _, children) = &resp.matches[0];
+        assert_eq!(*entity, EntityId::from(parent));
+        assert_eq!(children[0].entity, EntityId::from(child));
+        let json = serde_json::to_value(&resp.matches[0]).unwrap();
+        assert_eq!(json[2][0]["components"]["Health"]["health"], 10);
+    }
+
+    #[test]
+    fn scopes_matches_to_root_subtree() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.register::<Health>();
+        let grandchild = world.spawn(Health { health: 5 }).id();
+        let child = world
+            .spawn((Location { city: "NYC".into() }, Health { health: 10 }))
+            .push_children(&[grandchild])
+            .id();
+        let root = world
+            .spawn(Location { city: "SLC".into() })
+            .push_children(&[child])
+            .id();
+        world.spawn((Location { city: "LA".into() }, Health { health: 20 }));
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "Subtree".into(),
+                fetch: vec!["Health".into()],
+                root: Some(EntityId::from(root)),
+                layout: ResponseLayout::Columns,
+                children: Some(Box::new(ChildQuery {
+                    fetch: vec!["Health".into()],
+                    ..default()
+                })),
+                ..default()
+            },
+            &world,
+        );
+        let columns = api
+            .run_query(&world, &"Subtree".to_string())
+            .columns
+            .unwrap();
+        assert_eq!(
+            columns.entities,
+            [EntityId::from(child), EntityId::from(grandchild)]
+        );
+        // Aligned with the entities, the grandchild has no children of its own
+        assert_eq!(columns.children.len(), 2);
+        assert_eq!(columns.children[0][0].entity, EntityId::from(grandchild));
+        assert!(columns.children[1].is_empty());
+    }
+
+    #[test]
+    fn sorts_and_pages_matches() {
+        let mut world = World::new();
+        world.register::<Health>();
+        for health in [50, 99, 40] {
+            world.spawn(Health { health });
+        }
+
+        let api = EcsSubApi::default();
+        api.subscribe_components(
+            QuerySubReq {
+                id: "TopHealth".into(),
+                fetch: vec!["Health".into()],
+                sort: Some(SortBy {
+                    field: "Health.health".into(),
+                    descending: true,
+                }),
+                limit: Some(2),
+                ..default()
+            },
+            &world,
+        );
+        let resp = api.run_query(&world, &"TopHealth".to_string());
+        assert_eq!(resp.total, Some(3));
+        let healths: Vec<_> = resp
+            .matches
+            .iter()
+            .map(|QueryMatch(_, components, _)| components["Health"].get().to_string())
+            .collect();
+        assert_eq!(healths, vec![r#"{"health":99}"#, r#"{"health":50}"#]);
+    }
+
+    #[test]
+    fn rejects_sort_by_unknown_component() {
+        let mut world = World::new();
+        world.register::<Health>();
+        for health in [50, 99] {
+            world.spawn(Health { health });
+        }
+
+        let registry = world.resource::<ComponentIdRegistry>();
+        assert!(registry.try_short_name("Helth").is_none());
+        let api = EcsSubApi::default();
+        let query = QuerySubReq {
+            id: "Typo".into(),
+            fetch: vec!["Health".into()],
+            sort: Some(SortBy {
+                field: "Helth.health".into(),
+                descending: true,
+            }),
+            ..default()
+        };
+        let unknown = RequestError::UnknownComponent("Helth".into());
+        assert_eq!(
+            api.subscribe_components_for(None, query.clone(), &world),
+            Err(unknown.clone())
+        );
+        assert!(api.queries.read().unwrap().is_empty());
+        assert_eq!(api.query_once(query, &world).err(), Some(unknown));
+    }
+
+    #[derive(Debug, Reflect, serde::Serialize, serde::Deserialize)]
+    #[reflect(Serialize, Deserialize)]
+    struct Damage {
+        pub amount: u32,
+    }
+
+    #[test]
+    fn streams_subscribed_events() {
+        let mut world = World::new();
+        let api = EcsSubApi::default();
+        assert_eq!(
+            api.subscribe_events("Damage".into(), &world),
+            Err(RequestError::UnknownEvent("Damage".into()))
+        );
+
+        world.register_event::<Damage>();
+        assert_eq!(
+            api.subscribe_events("Dmg".into(), &world),
+            Err(RequestError::UnknownEvent("Dmg".into()))
+        );
+        api.subscribe_events("Damage".into(), &world).unwrap();
+        let payload = RawValue::from_string(r#"{"amount":5}"#.into()).unwrap();
+        events::send_event(&mut world, "Damage", &payload).unwrap();
+
+        let resps = api.run_all_event_subs(&world);
+        assert_eq!(resps.len(), 1);
+        let (owner, resp) = &resps[0];
+        assert_eq!(*owner, None);
+        assert_eq!(resp.event, "Damage");
+        assert_eq!(resp.events[0].get(), r#"{"amount":5}"#);
+        assert!(api.run_all_event_subs(&world).is_empty());
+    }
+
+    #[test]
+    fn stamps_events_since_last_sent() {
+        let mut world = World::new();
+        world.register_event::<Damage>();
+        let api = EcsSubApi::default();
+        api.subscribe_events("Damage".into(), &world).unwrap();
+        let subscribed = world.read_change_tick();
+        let payload = RawValue::from_string(r#"{"amount":5}"#.into()).unwrap();
+
+        // Frames without events send nothing, the next response still covers them
+        world.clear_trackers();
+        assert!(api.run_all_event_subs(&world).is_empty());
+        world.clear_trackers();
+        events::send_event(&mut world, "Damage", &payload).unwrap();
+        let first = api.run_all_event_subs(&world).remove(0).1.stamp;
+        assert_eq!(first.since_tick, subscribed);
+        assert_eq!(first.tick, world.read_change_tick());
+        assert_eq!(first.seq, 1);
+
+        world.clear_trackers();
+        events::send_event(&mut world, "Damage", &payload).unwrap();
+        let second = api.run_all_event_subs(&world).remove(0).1.stamp;
+        assert_eq!(second.since_tick, first.tick);
+        assert_eq!(second.seq, 2);
+    }
+
+    #[derive(Debug, Reflect, serde::Serialize)]
+    #[reflect(Serialize)]
+    struct Heal {
+        pub amount: u32,
+    }
+
+    #[test]
+    fn sends_events_from_clients() {
+        let mut world = World::new();
+        let payload = RawValue::from_string(r#"{"amount":5}"#.into()).unwrap();
+        assert_eq!(
+            events::send_event(&mut world, "Damage", &payload),
+            Err(RequestError::UnknownEvent("Damage".into()))
+        );
+
+        world.register_event::<Damage>();
+        world.register_event::<Heal>();
+        events::send_event(&mut world, "Damage", &payload).unwrap();
+        let damage = world.resource::<Events<Damage>>();
+        let sent: Vec<_> = damage.get_reader().iter(damage).map(|d| d.amount).collect();
+        assert_eq!(sent, [5]);
+
+        assert_eq!(
+            events::send_event(&mut world, "Dmg", &payload),
+            Err(RequestError::UnknownEvent("Dmg".into()))
+        );
+        assert_eq!(
+            events::send_event(&mut world, "Heal", &payload),
+            Err(RequestError::NotDeserializable("Heal".into()))
+        );
+        let wrong = RawValue::from_string(r#"{"amount":"five"}"#.into()).unwrap();
+        assert!(matches!(
+            events::send_event(&mut world, "Damage", &wrong),
+            Err(RequestError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn counts_grouped_by_field() {
+        let mut world = World::new();
+        world.register::<Location>();
+        for city in ["NYC", "SLC", "NYC"] {
+            world.spawn(Location { city: city.into() });
+        }
+
+        let api = EcsSubApi::default();
+        api.subscribe_aggregate(
+            AggregateSubReq {
+                id: "PerCity".into(),
+                fetch: vec!["Location".into()],
+                filter: vec![],
+                op: AggregateOp::Count,
+                group_by: Some("Location.city".into()),
+            },
+            &world,
+        )
+        .unwrap();
+        let resp = api.run_all_aggregates(&world).remove(0).1;
+        let mut groups = resp.groups;
+        groups.sort_by(|a, b| b.count.cmp(&a.count));
+        assert_eq!(groups[0].key, Some(FieldValue::Str("NYC".into())));
+        assert_eq!(groups[0].count, 2);
+        assert_eq!(groups[1].count, 1);
+
+        world.clear_trackers();
+        let next = api.run_all_aggregates(&world).remove(0).1.stamp;
+        assert_eq!(next.since_tick, resp.stamp.tick);
+        assert_eq!(next.seq, resp.stamp.seq + 1);
+    }
 }
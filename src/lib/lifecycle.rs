@@ -0,0 +1,99 @@
+use bevy::prelude::*;
+
+use crate::{aggregate::AggregateSubReq, ConnectionId, EcsSubApi, QueryId, QuerySubReq, ShortName};
+
+pub struct ClientConnected {
+    pub connection: ConnectionId,
+}
+
+pub struct ClientDisconnected {
+    pub connection: ConnectionId,
+}
+
+/// Query and aggregate subscriptions can share an id, the kind tells them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionKind {
+    Query,
+    Aggregate,
+    Events,
+}
+
+pub enum SubscriptionReq {
+    Query(QuerySubReq),
+    Aggregate(AggregateSubReq),
+    Events(ShortName),
+}
+
+impl SubscriptionReq {
+    /// Id of the subscription, the event's short name for event subscriptions
+    pub fn id(&self) -> &str {
+        match self {
+            SubscriptionReq::Query(req) => &req.id,
+            SubscriptionReq::Aggregate(req) => &req.id,
+            SubscriptionReq::Events(short_name) => short_name,
+        }
+    }
+
+    pub fn kind(&self) -> SubscriptionKind {
+        match self {
+            SubscriptionReq::Query(_) => SubscriptionKind::Query,
+            SubscriptionReq::Aggregate(_) => SubscriptionKind::Aggregate,
+            SubscriptionReq::Events(_) => SubscriptionKind::Events,
+        }
+    }
+}
+
+pub struct SubscriptionAdded {
+    /// `None` for subscriptions made by the app itself rather than a client
+    pub connection: Option<ConnectionId>,
+    pub req: SubscriptionReq,
+}
+
+pub struct SubscriptionRemoved {
+    pub connection: Option<ConnectionId>,
+    pub kind: SubscriptionKind,
+    /// The event's short name for event subscriptions
+    pub id: QueryId,
+}
+
+/// Recorded by `EcsSubApi`, which only ever sees `&World`, until `flush_lifecycle_events`
+/// sends them as Bevy events
+pub enum LifecycleEvent {
+    ClientConnected(ClientConnected),
+    ClientDisconnected(ClientDisconnected),
+    SubscriptionAdded(SubscriptionAdded),
+    SubscriptionRemoved(SubscriptionRemoved),
+}
+
+/// Lets systems react to clients coming and going, e.g. pausing simulations nobody watches
+pub struct LifecyclePlugin;
+
+impl Plugin for LifecyclePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ClientConnected>()
+            .add_event::<ClientDisconnected>()
+            .add_event::<SubscriptionAdded>()
+            .add_event::<SubscriptionRemoved>()
+            .add_system_to_stage(CoreStage::First, flush_lifecycle_events);
+    }
+}
+
+pub fn flush_lifecycle_events(
+    api: Option<Res<EcsSubApi>>,
+    mut connected: EventWriter<ClientConnected>,
+    mut disconnected: EventWriter<ClientDisconnected>,
+    mut added: EventWriter<SubscriptionAdded>,
+    mut removed: EventWriter<SubscriptionRemoved>,
+) {
+    let Some(api) = api else {
+        return;
+    };
+    for event in api.lifecycle.lock().unwrap().drain(..) {
+        match event {
+            LifecycleEvent::ClientConnected(event) => connected.send(event),
+            LifecycleEvent::ClientDisconnected(event) => disconnected.send(event),
+            LifecycleEvent::SubscriptionAdded(event) => added.send(event),
+            LifecycleEvent::SubscriptionRemoved(event) => removed.send(event),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::stats::{AdminReport, ConnectionInfo, SubscriptionStatsSnapshot};
+
+/// Process-wide counters that outlive single connections. Rendered in the Prometheus
+/// text format together with gauges taken from an `AdminReport`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+    receive_errors: AtomicU64,
+    frames_skipped: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_receive_error(&self) {
+        self.receive_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_frame_skipped(&self) {
+        self.frames_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, report: &AdminReport) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+            writeln!(out, "# HELP ecs_sub_{name} {help}").unwrap();
+            writeln!(out, "# TYPE ecs_sub_{name} {kind}").unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "ecs_sub_{name}{labels} {value}").unwrap();
+            }
+        };
+        let total =
+            |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed) as f64)];
+
+        metric(
+            "messages_sent_total",
+            "counter",
+            "Messages written to client sockets",
+            total(&self.messages_sent),
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Bytes written to client sockets after compression",
+            total(&self.bytes_sent),
+        );
+        metric(
+            "send_errors_total",
+            "counter",
+            "Failed socket writes",
+            total(&self.send_errors),
+        );
+        metric(
+            "receive_errors_total",
+            "counter",
+            "Unreadable client messages",
+            total(&self.receive_errors),
+        );
+        metric(
+            "frames_skipped_total",
+            "counter",
+            "Frames not run because results of earlier frames were still being dispatched",
+            total(&self.frames_skipped),
+        );
+
+        let connections = |value: &dyn Fn(&ConnectionInfo) -> f64| -> Vec<(String, f64)> {
+            report
+                .connections
+                .iter()
+                .map(|info| (format!("{{connection=\"{}\"}}", info.id), value(info)))
+                .collect()
+        };
+        metric(
+            "connections",
+            "gauge",
+            "Open client connections",
+            vec![(String::new(), report.connections.len() as f64)],
+        );
+        metric(
+            "connection_queue_depth",
+            "gauge",
+            "Messages waiting in a connection's outbound queue",
+            connections(&|info| info.queue.depth as f64),
+        );
+        metric(
+            "connection_queue_dropped_total",
+            "counter",
+            "Messages dropped or coalesced because a connection's queue was full",
+            connections(&|info| info.queue.dropped as f64),
+        );
+        metric(
+            "connection_bytes_sent_total",
+            "counter",
+            "Bytes written to a connection's socket",
+            connections(&|info| info.bytes_sent as f64),
+        );
+
+        // Summed per owner, query ids are picked by clients and would make one series each
+        let mut owners: BTreeMap<String, Vec<&SubscriptionStatsSnapshot>> = BTreeMap::new();
+        for info in &report.subscriptions {
+            let owner = info
+                .connection
+                .map_or_else(|| "app".to_string(), |connection| connection.to_string());
+            owners.entry(owner).or_default().push(&info.stats);
+        }
+        let subscriptions =
+            |value: &dyn Fn(&SubscriptionStatsSnapshot) -> f64| -> Vec<(String, f64)> {
+                owners
+                    .iter()
+                    .map(|(owner, stats)| {
+                        let total = stats.iter().map(|stats| value(stats)).sum();
+                        (format!("{{connection=\"{owner}\"}}"), total)
+                    })
+                    .collect()
+            };
+        metric(
+            "subscriptions",
+            "gauge",
+            "Subscriptions of a connection, or of the app itself",
+            subscriptions(&|_| 1.0),
+        );
+        metric(
+            "subscription_matches",
+            "gauge",
+            "Matches returned by the last runs of a connection's subscriptions",
+            subscriptions(&|stats| stats.matches as f64),
+        );
+        metric(
+            "subscription_runs_total",
+            "counter",
+            "Runs of a connection's subscriptions",
+            subscriptions(&|stats| stats.runs as f64),
+        );
+        metric(
+            "subscription_run_seconds_total",
+            "counter",
+            "Main thread time spent running a connection's subscriptions",
+            subscriptions(&|stats| stats.run_time_total_us as f64 / 1e6),
+        );
+        metric(
+            "subscription_serialize_seconds",
+            "gauge",
+            "Time the last responses of a connection's subscriptions took to serialize",
+            subscriptions(&|stats| stats.serialize_time_us as f64 / 1e6),
+        );
+        metric(
+            "subscription_bytes_queued_total",
+            "counter",
+            "Response bytes queued for a connection's subscriptions",
+            subscriptions(&|stats| stats.bytes_queued as f64),
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{stats::SubscriptionInfo, QuerySubReq};
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.record_sent(10);
+        metrics.record_sent(5);
+        let info = |connection, id: &str, runs| SubscriptionInfo {
+            connection,
+            req: QuerySubReq {
+                id: id.into(),
+                ..Default::default()
+            },
+            stats: SubscriptionStatsSnapshot {
+                runs,
+                ..Default::default()
+            },
+        };
+        let report = AdminReport {
+            connections: Vec::new(),
+            subscriptions: vec![
+                info(Some(3), "a\"b", 2),
+                info(Some(3), "c", 3),
+                info(None, "d", 1),
+            ],
+        };
+
+        let text = metrics.render(&report);
+        assert!(text.contains("# TYPE ecs_sub_bytes_sent_total counter\n"));
+        assert!(text.contains("ecs_sub_bytes_sent_total 15\n"));
+        assert!(text.contains("ecs_sub_subscriptions{connection=\"3\"} 2\n"));
+        assert!(text.contains("ecs_sub_subscription_runs_total{connection=\"3\"} 5\n"));
+        assert!(text.contains("ecs_sub_subscription_runs_total{connection=\"app\"} 1\n"));
+        assert!(!text.contains("id="));
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+use crate::QueryId;
+
+/// What to do when a connection's outbound queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room
+    #[default]
+    DropOldest,
+    /// Replace the queued message of the same subscription with the newer one in its place,
+    /// so the subscription keeps its turn, falling back to dropping the oldest. Coalesced
+    /// change streams lose deltas, clients can spot the gap in sequence numbers and resync.
+    Coalesce,
+    /// Close the connection
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            "coalesce" => Some(OverflowPolicy::Coalesce),
+            "disconnect" => Some(OverflowPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueMetrics {
+    pub depth: usize,
+    pub peak_depth: usize,
+    pub dropped: u64,
+}
+
+struct QueueState<T, K> {
+    items: VecDeque<(Option<K>, T)>,
+    closed: bool,
+}
+
+/// Bounded per-connection queue between the frame producing messages and the task
+/// writing them to the socket, so a slow client only ever holds up itself. Items are
+/// coalesced by their key `K`.
+pub struct OutboundQueue<T, K = QueryId> {
+    state: Mutex<QueueState<T, K>>,
+    notify: Notify,
+    /// Wakes every `closed` waiter, `notify` only ever has the one `pop`
+    closed_notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    depth: AtomicUsize,
+    peak_depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl<T, K: PartialEq> OutboundQueue<T, K> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "outbound queue capacity must be at least 1");
+        OutboundQueue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            notify: Notify::new(),
+            closed_notify: Notify::new(),
+            capacity,
+            policy,
+            depth: AtomicUsize::new(0),
+            peak_depth: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues `item` without blocking, applying the overflow policy if the queue is full.
+    /// `key` identifies the subscription the item belongs to for coalescing.
+    /// Returns false if the queue is closed, either already or because of the overflow.
+    pub fn push(&self, key: Option<K>, item: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                }
+                OverflowPolicy::Coalesce => {
+                    let same = key.as_ref().and_then(|key| {
+                        state
+                            .items
+                            .iter()
+                            .position(|(queued, _)| queued.as_ref() == Some(key))
+                    });
+                    match same {
+                        Some(i) => {
+                            state.items[i].1 = item;
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                            return true;
+                        }
+                        None => {
+                            state.items.pop_front();
+                        }
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    state.closed = true;
+                    state.items.clear();
+                    self.depth.store(0, Ordering::Relaxed);
+                    self.notify.notify_one();
+                    self.closed_notify.notify_waiters();
+                    return false;
+                }
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        state.items.push_back((key, item));
+        let depth = state.items.len();
+        self.depth.store(depth, Ordering::Relaxed);
+        self.peak_depth.fetch_max(depth, Ordering::Relaxed);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Waits for the next item, returns `None` once the queue is closed
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some((_, item)) = state.items.pop_front() {
+                    self.depth.store(state.items.len(), Ordering::Relaxed);
+                    return Some(item);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+        self.closed_notify.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Waits until the queue is closed, by `close` or by the overflow policy
+    pub async fn closed(&self) {
+        loop {
+            // Registered before checking, so a close in between still wakes it
+            let notified = self.closed_notify.notified();
+            if self.is_closed() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.depth.load(Ordering::Relaxed),
+            peak_depth: self.peak_depth.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coalesces_same_subscription() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::Coalesce);
+        assert!(queue.push(Some("a".into()), 1));
+        assert!(queue.push(Some("b".into()), 2));
+        assert!(queue.push(Some("a".into()), 3));
+
+        let state = queue.state.lock().unwrap();
+        let items: Vec<_> = state.items.iter().map(|(_, item)| *item).collect();
+        assert_eq!(items, vec![3, 2]);
+        assert_eq!(queue.metrics().dropped, 1);
+    }
+
+    #[test]
+    fn disconnect_closes_queue() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::Disconnect);
+        assert!(queue.push(None, 1));
+        assert!(!queue.push(None, 2));
+        assert!(queue.is_closed());
+        // Already closed, so this must not wait
+        futures_util::FutureExt::now_or_never(queue.closed()).unwrap();
+    }
+}
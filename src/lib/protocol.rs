@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::error::Error;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    aggregate::{AggregateSubReq, AggregateSubResp},
+    error::RequestError,
+    events::EventSubResp,
+    spatial::Region,
+    stats::AdminReport,
+    transport::Frame,
+    Compression, ConnectionId, QueryId, QuerySubJson, QuerySubReq, ShortName, Stamp,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientMsg {
+    Subscribe(QuerySubReq),
+    /// Replaces the request of an existing subscription, keeping its tracking state
+    Modify(QuerySubReq),
+    Unsubscribe(QueryId),
+    /// Asks for the next response to be a full snapshot again
+    Resync(QueryId),
+    SetRegion {
+        id: QueryId,
+        region: Option<Region>,
+    },
+    SubscribeAggregate(AggregateSubReq),
+    SubscribeEvents(ShortName),
+    UnsubscribeEvents(ShortName),
+    SendEvent {
+        event: ShortName,
+        payload: Box<RawValue>,
+    },
+    /// Asks for an `AdminReport` of every connection and subscription
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMsg {
+    Ack {
+        req: QuerySubReq,
+        stamp: Stamp,
+    },
+    QuerySubResp(QuerySubJson),
+    EventSubResp(EventSubResp),
+    AggregateSubResp(AggregateSubResp),
+    /// Every result produced for the connection in one frame, sent instead of the
+    /// individual messages when the connection asked for batching
+    Frame {
+        stamp: Stamp,
+        msgs: Vec<ServerMsg>,
+    },
+    Admin {
+        report: AdminReport,
+        stamp: Stamp,
+    },
+    /// A request of the connection was rejected
+    Error {
+        error: RequestError,
+        stamp: Stamp,
+    },
+    Text {
+        text: String,
+        stamp: Stamp,
+    },
+}
+
+/// Identifies what a queued message would be replaced by when coalescing. Queries and
+/// aggregates sharing an id, or subscriptions of different owners, are kept apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoalesceKey {
+    Query(Option<ConnectionId>, QueryId),
+    Aggregate(Option<ConnectionId>, QueryId),
+    /// Every batched frame of a connection
+    Frame,
+}
+
+impl ServerMsg {
+    pub fn stamp(&self) -> &Stamp {
+        match self {
+            ServerMsg::QuerySubResp(resp) => &resp.stamp,
+            ServerMsg::EventSubResp(resp) => &resp.stamp,
+            ServerMsg::AggregateSubResp(resp) => &resp.stamp,
+            ServerMsg::Ack { stamp, .. }
+            | ServerMsg::Frame { stamp, .. }
+            | ServerMsg::Admin { stamp, .. }
+            | ServerMsg::Error { stamp, .. }
+            | ServerMsg::Text { stamp, .. } => stamp,
+        }
+    }
+
+    /// Subscription the message belongs to, messages with the same key can be coalesced.
+    /// `owner` is the connection that subscribed, `None` for the app's own subscriptions.
+    pub fn coalesce_key(&self, owner: Option<ConnectionId>) -> Option<CoalesceKey> {
+        match self {
+            ServerMsg::QuerySubResp(resp) => Some(CoalesceKey::Query(owner, resp.id.clone())),
+            ServerMsg::AggregateSubResp(resp) => {
+                Some(CoalesceKey::Aggregate(owner, resp.id.clone()))
+            }
+            ServerMsg::Frame { .. } => Some(CoalesceKey::Frame),
+            _ => None,
+        }
+    }
+
+    pub fn to_frame(&self, compression: Compression) -> Frame {
+        let mut json = Vec::new();
+        self.write_json(&mut json).unwrap();
+        match compression {
+            Compression::None => Frame::Text(String::from_utf8(json).unwrap()),
+            _ => Frame::Binary(compression.compress(&json).unwrap()),
+        }
+    }
+
+    /// Same json as `serde_json::to_writer`, but query responses are copied in as they
+    /// were written instead of going through a `RawValue`
+    fn write_json(&self, out: &mut Vec<u8>) -> serde_json::Result<()> {
+        match self {
+            ServerMsg::QuerySubResp(resp) => {
+                out.extend_from_slice(br#"{"QuerySubResp":"#);
+                out.extend_from_slice(resp.json.as_bytes());
+                out.push(b'}');
+            }
+            ServerMsg::Frame { stamp, msgs } => {
+                out.extend_from_slice(br#"{"Frame":{"stamp":"#);
+                serde_json::to_writer(&mut *out, stamp)?;
+                out.extend_from_slice(br#","msgs":["#);
+                for (i, msg) in msgs.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b',');
+                    }
+                    msg.write_json(out)?;
+                }
+                out.extend_from_slice(b"]}}");
+            }
+            msg => serde_json::to_writer(out, msg)?,
+        }
+        Ok(())
+    }
+
+    /// Binary frames are decompressed, plain json in them is read as is when the
+    /// connection uses no compression
+    pub fn from_frame(frame: Frame, compression: Compression) -> Result<ServerMsg, Box<dyn Error>> {
+        let bytes = match frame {
+            Frame::Text(text) => text.into_bytes(),
+            Frame::Binary(bytes) => compression.decompress(&bytes)?,
+        };
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub fn to_message(&self, compression: Compression) -> Message {
+        self.to_frame(compression).into()
+    }
+
+    pub fn from_message(
+        msg: Message,
+        compression: Compression,
+    ) -> Result<ServerMsg, Box<dyn Error>> {
+        let frame = match msg {
+            Message::Text(text) => Frame::Text(text),
+            Message::Binary(bytes) => Frame::Binary(bytes),
+            other => return Err(format!("Unexpected message: {other:?}").into()),
+        };
+        ServerMsg::from_frame(frame, compression)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ConnectOptions;
+
+    #[test]
+    fn roundtrips_batched_frames() {
+        let stamp = Stamp {
+            frame: 7,
+            timestamp_ms: 1000,
+            since_tick: 3,
+            tick: 9,
+            seq: 2,
+        };
+        let resp = serde_json::json!({
+            "id": "Locations",
+            "stamp": stamp,
+            "snapshot": true,
+            "matches": [[{"index": 0, "generation": 0}, {"Location": {"city": "NYC"}}]],
+        });
+        let frame = ServerMsg::Frame {
+            stamp,
+            msgs: vec![
+                ServerMsg::QuerySubResp(serde_json::from_str(&resp.to_string()).unwrap()),
+                ServerMsg::Text {
+                    text: "hi".into(),
+                    stamp,
+                },
+            ],
+        };
+        let json = serde_json::to_string(&frame).unwrap();
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            for batch in [false, true] {
+                let options = ConnectOptions { compression, batch };
+                assert_eq!(ConnectOptions::from_query(&options.to_query()), options);
+            }
+
+            if compression == Compression::None {
+                assert_eq!(frame.to_frame(compression).as_bytes(), json.as_bytes());
+            }
+            let msg = frame.to_message(compression);
+            assert_eq!(msg.is_text(), compression == Compression::None);
+            let read = ServerMsg::from_message(msg, compression).unwrap();
+            assert_eq!(serde_json::to_string(&read).unwrap(), json);
+            let ServerMsg::Frame {
+                stamp: read_stamp,
+                msgs,
+            } = &read
+            else {
+                panic!("expected a frame, got {read:?}");
+            };
+            assert_eq!(*read_stamp, stamp);
+            let [ServerMsg::QuerySubResp(query), ServerMsg::Text { .. }] = &msgs[..] else {
+                panic!("expected a query response and a text, got {msgs:?}");
+            };
+            assert_eq!((query.id.as_str(), query.stamp), ("Locations", stamp));
+            assert_eq!(query.parse().unwrap().matches.len(), 1);
+        }
+    }
+
+    #[test]
+    fn keys_coalescing_by_kind_and_owner() {
+        let stamp = Stamp::default();
+        let json = br#"{"id":"a","matches":[]}"#;
+        let query = ServerMsg::QuerySubResp(QuerySubJson::new("a".into(), stamp, json).unwrap());
+        let aggregate = ServerMsg::AggregateSubResp(AggregateSubResp {
+            id: "a".into(),
+            stamp,
+            groups: Vec::new(),
+        });
+        assert_ne!(query.coalesce_key(None), aggregate.coalesce_key(None));
+        assert_ne!(query.coalesce_key(None), query.coalesce_key(Some(1)));
+        assert_eq!(query.coalesce_key(Some(1)), query.coalesce_key(Some(1)));
+        let text = ServerMsg::Text {
+            text: "a".into(),
+            stamp,
+        };
+        assert_eq!(text.coalesce_key(None), None);
+    }
+}
@@ -1,6 +1,10 @@
 use bevy::reflect::{erased_serde, GetTypeRegistration};
 use bevy::{
-    ecs::component::ComponentId,
+    ecs::{
+        component::{ComponentId, ComponentTicks},
+        event::Event,
+        world::EntityRef,
+    },
     prelude::*,
     reflect::{ReflectFromPtr, TypeRegistry},
     utils::HashMap,
@@ -12,12 +16,16 @@ use serde_json::value::RawValue;
 use std::sync::RwLock;
 use std::{any::TypeId, io};
 
+use crate::events::EventRegistry;
+
 pub type ShortName = String;
 
 #[derive(Default, Resource)]
 pub struct ComponentIdRegistry {
     short_names: HashMap<ShortName, ComponentId>,
     type_ids: HashMap<TypeId, ComponentId>,
+    /// `EntityRef` only exposes ticks for statically known components
+    change_ticks: HashMap<ComponentId, fn(&EntityRef) -> Option<ComponentTicks>>,
 }
 
 impl ComponentIdRegistry {
@@ -29,38 +37,76 @@ impl ComponentIdRegistry {
         self.short_names.insert(short_name.into(), component_id);
         self.type_ids
             .insert(std::any::TypeId::of::<T>(), component_id);
+        self.change_ticks.insert(component_id, |entity| {
+            entity.get_change_ticks::<T>().copied()
+        });
     }
 
     pub fn short_name(&self, short_name: impl AsRef<str>) -> ComponentId {
         self.short_names.get(short_name.as_ref()).unwrap().clone()
     }
+
+    /// Same as `short_name` for names coming from clients, `None` if nothing registered it
+    pub fn try_short_name(&self, short_name: impl AsRef<str>) -> Option<ComponentId> {
+        self.short_names.get(short_name.as_ref()).copied()
+    }
+
+    pub fn short_names(&self) -> impl Iterator<Item = (&ShortName, ComponentId)> {
+        self.short_names
+            .iter()
+            .map(|(short_name, component_id)| (short_name, *component_id))
+    }
+
+    pub fn change_ticks(
+        &self,
+        entity: &EntityRef,
+        component_id: ComponentId,
+    ) -> Option<ComponentTicks> {
+        (self.change_ticks.get(&component_id).unwrap())(entity)
+    }
 }
 
 pub trait RegistryExt {
     fn register<T: Component + GetTypeRegistration>(&mut self);
+    fn register_event<T: Event + Reflect + GetTypeRegistration>(&mut self);
+}
+
+fn register_type<T: GetTypeRegistration>(world: &mut World) -> ShortName {
+    let type_registry = world.get_resource_or_insert_with(AppTypeRegistry::default);
+    let mut type_registry = type_registry.write();
+    type_registry.register::<T>();
+    let short_name = type_registry
+        .get(std::any::TypeId::of::<T>())
+        .unwrap()
+        .short_name();
+    short_name.to_string()
 }
 
 impl RegistryExt for World {
     fn register<T: Component + GetTypeRegistration>(&mut self) {
         use bevy::prelude::*;
         let component_id = self.init_component::<T>();
-        let short_name = {
-            let type_registry = self.get_resource_or_insert_with(AppTypeRegistry::default);
-            let mut type_registry = type_registry.write();
-            type_registry.register::<T>();
-            let short_name = type_registry
-                .get(std::any::TypeId::of::<T>())
-                .unwrap()
-                .short_name();
-            short_name.to_string()
-        };
+        let short_name = register_type::<T>(self);
         let mut registry = self.get_resource_or_insert_with(ComponentIdRegistry::default);
         registry.register::<T>(component_id, short_name);
     }
+
+    fn register_event<T: Event + Reflect + GetTypeRegistration>(&mut self) {
+        self.init_resource::<Events<T>>();
+        let short_name = register_type::<T>(self);
+        let mut registry = self.get_resource_or_insert_with(EventRegistry::default);
+        registry.register::<T>(short_name);
+    }
 }
 
 impl RegistryExt for App {
     fn register<T: Component + GetTypeRegistration>(&mut self) {
         self.world.register::<T>()
     }
+
+    fn register_event<T: Event + Reflect + GetTypeRegistration>(&mut self) {
+        // Also adds the system that swaps the event buffers every frame
+        self.add_event::<T>();
+        self.world.register_event::<T>()
+    }
 }
@@ -0,0 +1,679 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bevy::{core::FrameCount, prelude::*, utils::tracing::Instrument};
+use bevy_tokio_tasks::{TaskContext, TokioTasksRuntime};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    http::HeaderValue,
+};
+
+use crate::{
+    events,
+    lifecycle::LifecyclePlugin,
+    metrics::Metrics,
+    outbound::{OutboundQueue, OverflowPolicy},
+    protocol::{ClientMsg, CoalesceKey, ServerMsg},
+    stats::{AdminReport, ConnectionInfo, ConnectionStats, SubscriptionStats},
+    transport::{self, FrameSink, FrameStream},
+    ConnectOptions, ConnectionId, EcsSubApi, Stamp,
+};
+
+/// Runs every subscription once per frame and routes the results to the open connections.
+/// Needs `TokioTasksPlugin`, clients are accepted by `network` and the listeners in
+/// `transport`. Adds `LifecyclePlugin` unless the app already did.
+pub struct ServerPlugin {
+    /// Messages buffered per connection before the overflow policy kicks in
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for ServerPlugin {
+    fn default() -> Self {
+        ServerPlugin {
+            queue_capacity: 64,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        // Without it the lifecycle events `EcsSubApi` records would never be drained
+        if !app.is_plugin_added::<LifecyclePlugin>() {
+            app.add_plugin(LifecyclePlugin);
+        }
+        app.insert_resource(Connections::new(self.queue_capacity, self.overflow_policy))
+            .init_resource::<EcsSubApi>()
+            .add_startup_system(start_dispatcher)
+            .add_system(query_runner);
+    }
+}
+
+/// Frames produced but not yet dispatched before `query_runner` starts skipping frames
+const FRAMES_IN_FLIGHT: usize = 4;
+
+fn start_dispatcher(
+    mut commands: Commands,
+    runtime: Res<TokioTasksRuntime>,
+    connections: Res<Connections>,
+) {
+    let (dispatcher, frames) = channel(FRAMES_IN_FLIGHT);
+    commands.insert_resource(Dispatcher(dispatcher));
+    let connections = connections.clone();
+    runtime.spawn_background_task(move |_ctx| dispatch_frames(frames, connections));
+}
+
+/// Runs and serializes every subscription due this frame straight from the world through
+/// `ReflectSerialize`, spread over the `ComputeTaskPool`, then hands the results to
+/// `dispatch_frames`
+fn query_runner(world: &mut World, mut pending_events: Local<Vec<Routed>>) {
+    let frame = world.resource::<FrameCount>().0;
+    let _span = info_span!("query_runner", frame).entered();
+    let Some(dispatcher) = world.get_resource::<Dispatcher>() else {
+        debug!("Dispatcher res not yet inserted");
+        return;
+    };
+    let api = world.resource::<EcsSubApi>();
+    // `Events` only keep the last two frames, so the readers are drained every frame and
+    // their responses held back until the dispatcher takes a frame again
+    let events = api.run_all_event_subs(world).into_iter();
+    pending_events.extend(events.map(|(connection, resp)| Routed {
+        connection,
+        msg: ServerMsg::EventSubResp(resp),
+        stats: None,
+    }));
+    // Nothing else is run while the dispatcher is behind. Queries and aggregates pick up
+    // the skipped changes on their next run, so the skipped frames coalesce into it.
+    let permit = match dispatcher.0.try_reserve() {
+        Ok(permit) => permit,
+        Err(TrySendError::Full(())) => {
+            debug!("Dispatcher is behind, skipping frame {}", frame);
+            world
+                .resource::<Connections>()
+                .metrics
+                .record_frame_skipped();
+            return;
+        }
+        Err(TrySendError::Closed(())) => {
+            error!("Dispatcher task stopped");
+            return;
+        }
+    };
+    let mut results = Vec::new();
+    api.write_all_queries(world, |sub, resp| {
+        results.push(Routed {
+            connection: sub.connection,
+            stats: Some((sub.stats.clone(), resp.json.len())),
+            msg: ServerMsg::QuerySubResp(resp),
+        })
+    });
+    let aggregates = api.run_all_aggregates(world).into_iter();
+    results.extend(aggregates.map(|(connection, resp)| Routed {
+        connection,
+        msg: ServerMsg::AggregateSubResp(resp),
+        stats: None,
+    }));
+    results.append(&mut pending_events);
+    if !results.is_empty() {
+        // Ticks and sequence are filled in per connection by `dispatch`
+        let stamp = Stamp::new(world, 0, 0);
+        permit.send(Frame { stamp, results });
+    }
+}
+
+/// Everything produced in one frame, already serialized
+struct Frame {
+    stamp: Stamp,
+    results: Vec<Routed>,
+}
+
+/// A result and the connection that subscribed to it, see `routes_to`
+struct Routed {
+    connection: Option<ConnectionId>,
+    msg: ServerMsg,
+    /// Subscription to charge for the json bytes of every copy queued
+    stats: Option<(Arc<SubscriptionStats>, usize)>,
+}
+
+#[derive(Resource)]
+struct Dispatcher(pub Sender<Frame>);
+
+/// Queues each frame's results on the connections, taking frames one at a time so every
+/// connection still receives them in order
+async fn dispatch_frames(mut frames: Receiver<Frame>, connections: Connections) {
+    while let Some(Frame { stamp, results }) = frames.recv().await {
+        let span = info_span!(
+            "dispatch_frame",
+            frame = stamp.frame,
+            results = results.len()
+        );
+        for result in results.iter() {
+            trace!(msg = ?result.msg, "Result");
+        }
+        span.in_scope(|| dispatch(&connections, stamp, &results));
+    }
+}
+
+/// Only queues here, every connection's writer task drains its own queue so a slow
+/// client can't stall the others
+fn dispatch(connections: &Connections, stamp: Stamp, results: &[Routed]) {
+    for (id, connection) in connections.connections.lock().unwrap().iter_mut() {
+        let results: Vec<&Routed> = results
+            .iter()
+            .filter(|result| routes_to(result.connection, *id))
+            .collect();
+        let queued = if connection.options.batch {
+            if results.is_empty() {
+                continue;
+            }
+            // Frames the connection never got still count, so a gap in `seq` shows a drop
+            connection.last_frame = Stamp {
+                since_tick: connection.last_frame.tick,
+                seq: connection.last_frame.seq + 1,
+                ..stamp
+            };
+            connection.push(
+                Some(*id),
+                ServerMsg::Frame {
+                    stamp: connection.last_frame,
+                    msgs: results.iter().map(|result| result.msg.clone()).collect(),
+                },
+            )
+        } else {
+            results
+                .iter()
+                .all(|result| connection.push(result.connection, result.msg.clone()))
+        };
+        if !queued {
+            warn!("Connection {} closed: {:?}", id, connection.queue.metrics());
+            continue;
+        }
+        for (stats, len) in results.iter().filter_map(|result| result.stats.as_ref()) {
+            stats.record_queued(*len);
+        }
+    }
+}
+
+/// Results of a client's subscriptions only go to that client, those of the app's own
+/// subscriptions (`None`) go to every connection
+fn routes_to(owner: Option<ConnectionId>, connection: ConnectionId) -> bool {
+    owner.map_or(true, |owner| owner == connection)
+}
+
+struct Connection {
+    queue: Arc<OutboundQueue<ServerMsg, CoalesceKey>>,
+    options: ConnectOptions,
+    stats: Arc<ConnectionStats>,
+    /// Stamp of the last batched frame, the next one covers the changes after it
+    last_frame: Stamp,
+}
+
+impl Connection {
+    /// `owner` is the connection that subscribed to `msg`, see `routes_to`
+    fn push(&self, owner: Option<ConnectionId>, msg: ServerMsg) -> bool {
+        self.queue.push(msg.coalesce_key(owner), msg)
+    }
+}
+
+/// Every open connection, whatever transport it came in over, plus the process-wide
+/// metrics. Cheap to clone, all clones share the same connections.
+#[derive(Clone, Resource)]
+pub struct Connections {
+    connections: Arc<Mutex<HashMap<ConnectionId, Connection>>>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl Connections {
+    pub fn new(queue_capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        Connections {
+            connections: Arc::default(),
+            queue_capacity,
+            overflow_policy,
+            metrics: Arc::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Registers a connection that results get routed to from now on, its writer then
+    /// drains the returned queue
+    pub fn open(
+        &self,
+        options: ConnectOptions,
+    ) -> (
+        ConnectionId,
+        Arc<OutboundQueue<ServerMsg, CoalesceKey>>,
+        Arc<ConnectionStats>,
+    ) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(OutboundQueue::new(
+            self.queue_capacity,
+            self.overflow_policy,
+        ));
+        let stats = Arc::new(ConnectionStats::default());
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                queue: queue.clone(),
+                options,
+                stats: stats.clone(),
+                last_frame: Stamp::default(),
+            },
+        );
+        (id, queue, stats)
+    }
+
+    pub fn close(&self, id: ConnectionId) {
+        if let Some(connection) = self.connections.lock().unwrap().remove(&id) {
+            connection.queue.close();
+        }
+    }
+}
+
+pub fn admin_report(api: &EcsSubApi, connections: &Connections) -> AdminReport {
+    let subscriptions = api.subscription_infos();
+    let connections = connections
+        .connections
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, connection)| ConnectionInfo {
+            id: *id,
+            options: connection.options,
+            queue: connection.queue.metrics(),
+            messages_sent: connection.stats.messages_sent(),
+            bytes_sent: connection.stats.bytes_sent(),
+            subscriptions: subscriptions
+                .iter()
+                .filter(|info| info.connection == Some(*id))
+                .map(|info| info.req.id.clone())
+                .collect(),
+        })
+        .collect();
+    AdminReport {
+        connections,
+        subscriptions,
+    }
+}
+
+/// Accepts WebSocket connections, a failed accept or handshake only loses that client
+pub async fn network(ctx: TaskContext, connections: Connections, listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept a WebSocket connection: {}", e);
+                continue;
+            }
+        };
+        info!("Peer address: {}", addr);
+        // Handshakes run in their own task so a slow client doesn't hold up the others
+        let (ctx, connections) = (ctx.clone(), connections.clone());
+        tokio::spawn(async move {
+            // Options are negotiated through the connect uri's query string, the
+            // compression actually used is confirmed back to the client with a header
+            let mut options = ConnectOptions::default();
+            let handshake =
+                tokio_tungstenite::accept_hdr_async(stream, |req: &Request, mut resp: Response| {
+                    options = req
+                        .uri()
+                        .query()
+                        .map(ConnectOptions::from_query)
+                        .unwrap_or_default();
+                    resp.headers_mut().insert(
+                        crate::compression::COMPRESSION_HEADER,
+                        HeaderValue::from_static(options.compression.name()),
+                    );
+                    Ok(resp)
+                })
+                .await;
+            match handshake {
+                Ok(ws_stream) => spawn_connection(
+                    &ctx,
+                    &connections,
+                    transport::websocket(ws_stream),
+                    options,
+                    &addr.to_string(),
+                ),
+                Err(e) => warn!("WebSocket handshake with {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+/// Routes results to a new connection, over whichever transport it came in, and spawns
+/// the tasks writing to and reading from it
+pub fn spawn_connection(
+    ctx: &TaskContext,
+    connections: &Connections,
+    (write, read): (FrameSink, FrameStream),
+    options: ConnectOptions,
+    peer: &str,
+) {
+    let (id, queue, stats) = connections.open(options);
+    info!("New connection {}: {} ({:?})", id, peer, options);
+    let span = info_span!("connection", id, peer);
+    tokio::spawn(
+        outgoing(
+            write,
+            queue.clone(),
+            options,
+            stats,
+            connections.clone(),
+            id,
+        )
+        .instrument(span.clone()),
+    );
+    tokio::spawn(
+        incoming(ctx.clone(), read, queue, connections.metrics.clone(), id).instrument(span),
+    );
+}
+
+/// Drains a connection's queue into its socket until either side closes
+async fn outgoing(
+    mut write: FrameSink,
+    queue: Arc<OutboundQueue<ServerMsg, CoalesceKey>>,
+    options: ConnectOptions,
+    stats: Arc<ConnectionStats>,
+    connections: Connections,
+    id: ConnectionId,
+) {
+    while let Some(msg) = queue.pop().await {
+        let frame = msg.to_frame(options.compression);
+        let len = frame.as_bytes().len();
+        match write
+            .send(frame)
+            .instrument(debug_span!("send", bytes = len))
+            .await
+        {
+            Ok(()) => {
+                stats.record_sent(len);
+                connections.metrics.record_sent(len);
+            }
+            Err(e) => {
+                connections.metrics.record_send_error();
+                match e.kind() {
+                    io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::BrokenPipe => {
+                        error!("Caught: {}", e);
+                        break;
+                    }
+                    // Rejected before anything was written, e.g. for being larger than the
+                    // transport allows, so only this message is lost
+                    io::ErrorKind::InvalidInput => {
+                        error!("Dropped a {} byte message: {}", len, e)
+                    }
+                    _ => error!("Uncaught {}", e),
+                }
+            }
+        }
+    }
+    connections.close(id);
+    let _ = write.close().await;
+    info!("Connection {} removed", id);
+}
+
+async fn incoming(
+    mut ctx: TaskContext,
+    mut read: FrameStream,
+    queue: Arc<OutboundQueue<ServerMsg, CoalesceKey>>,
+    metrics: Arc<Metrics>,
+    connection: ConnectionId,
+) {
+    with_api(&mut ctx, move |api, _| api.connect(connection)).await;
+    let owner = Some(connection);
+    let mut replies = 0;
+    loop {
+        let msg = tokio::select! {
+            msg = read.next() => msg,
+            // Closed by `outgoing` or by the overflow policy, nothing can be replied anymore
+            () = queue.closed() => break,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        let Ok(msg) = msg else {
+            error!("{:?}", msg);
+            metrics.record_receive_error();
+            continue;
+        };
+        // Length-prefixed transports carry the json in binary frames
+        let msg: ClientMsg = match serde_json::from_slice(msg.as_bytes()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Unreadable client message: {}", e);
+                metrics.record_receive_error();
+                continue;
+            }
+        };
+        debug!(?msg, "Request");
+        // Replies are numbered per connection, like the responses of a subscription
+        let seq = replies + 1;
+        let reply = ctx
+            .run_on_main_thread(move |ctx| {
+                let api = ctx.world.remove_resource::<EcsSubApi>().unwrap_or_default();
+                let stamp = Stamp::new(ctx.world, ctx.world.read_change_tick(), seq);
+                let error = move |error| ServerMsg::Error { error, stamp };
+                let reply = match msg {
+                    ClientMsg::Subscribe(query) => Some(
+                        match api.subscribe_components_for(owner, query.clone(), ctx.world) {
+                            Ok(()) => ServerMsg::Ack { req: query, stamp },
+                            Err(e) => error(e),
+                        },
+                    ),
+                    ClientMsg::Modify(query) => {
+                        Some(match api.modify_subscription_for(owner, query, ctx.world) {
+                            Ok(req) => ServerMsg::Ack { req, stamp },
+                            Err(e) => error(e),
+                        })
+                    }
+                    ClientMsg::Unsubscribe(id) => api.unsubscribe(owner, &id).err().map(error),
+                    ClientMsg::Resync(id) => api.resync(owner, &id).err().map(error),
+                    ClientMsg::SetRegion { id, region } => {
+                        api.set_region(owner, &id, region).err().map(error)
+                    }
+                    ClientMsg::SubscribeAggregate(aggregate) => api
+                        .subscribe_aggregate_for(owner, aggregate, ctx.world)
+                        .err()
+                        .map(error),
+                    ClientMsg::SubscribeEvents(short_name) => api
+                        .subscribe_events_for(owner, short_name, ctx.world)
+                        .err()
+                        .map(error),
+                    ClientMsg::UnsubscribeEvents(short_name) => {
+                        api.unsubscribe_events(owner, &short_name).err().map(error)
+                    }
+                    ClientMsg::Admin => Some(ServerMsg::Admin {
+                        report: admin_report(&api, ctx.world.resource::<Connections>()),
+                        stamp,
+                    }),
+                    ClientMsg::SendEvent { event, payload } => {
+                        events::send_event(ctx.world, &event, &payload)
+                            .err()
+                            .map(error)
+                    }
+                };
+                ctx.world.insert_resource(api);
+                reply
+            })
+            .await;
+        if let Some(reply) = reply {
+            replies = seq;
+            if !queue.push(reply.coalesce_key(owner), reply) {
+                error!("Failed to reply, connection closed");
+            }
+        }
+    }
+    queue.close();
+    with_api(&mut ctx, move |api, _| api.disconnect(connection)).await;
+}
+
+/// Runs `f` on the main thread with the `EcsSubApi` taken out of the world, so it can be
+/// used together with `&mut World`
+pub async fn with_api(
+    ctx: &mut TaskContext,
+    f: impl FnOnce(&EcsSubApi, &mut World) + Send + 'static,
+) {
+    ctx.run_on_main_thread(move |ctx| {
+        let api = ctx.world.remove_resource::<EcsSubApi>().unwrap_or_default();
+        f(&api, ctx.world);
+        ctx.world.insert_resource(api);
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::{QuerySubReq, RegistryExt};
+
+    #[derive(Component, Reflect, serde::Serialize)]
+    #[reflect(Serialize)]
+    struct Location {
+        city: String,
+    }
+
+    #[test]
+    fn routes_results_to_their_owner() {
+        let connections = Connections::new(4, OverflowPolicy::default());
+        let (a, a_queue, _) = connections.open(ConnectOptions::default());
+        let (_, b_queue, _) = connections.open(ConnectOptions::default());
+        let routed = |connection: Option<ConnectionId>, text: &str| Routed {
+            connection,
+            msg: ServerMsg::Text {
+                text: text.into(),
+                stamp: Stamp::default(),
+            },
+            stats: None,
+        };
+        dispatch(
+            &connections,
+            Stamp::default(),
+            &[routed(Some(a), "a"), routed(None, "app")],
+        );
+
+        let drain = |queue: &OutboundQueue<ServerMsg, CoalesceKey>| {
+            let mut texts = Vec::new();
+            while let Some(Some(ServerMsg::Text { text, .. })) = queue.pop().now_or_never() {
+                texts.push(text);
+            }
+            texts
+        };
+        assert_eq!(drain(&a_queue), ["a", "app"]);
+        assert_eq!(drain(&b_queue), ["app"]);
+    }
+
+    #[test]
+    fn numbers_batched_frames_per_connection() {
+        let connections = Connections::new(4, OverflowPolicy::default());
+        let (a, queue, _) = connections.open(ConnectOptions {
+            batch: true,
+            ..default()
+        });
+        let text = ServerMsg::Text {
+            text: "a".into(),
+            stamp: Stamp::default(),
+        };
+        for tick in [5, 9] {
+            let results = [Routed {
+                connection: Some(a),
+                msg: text.clone(),
+                stats: None,
+            }];
+            let stamp = Stamp { tick, ..default() };
+            dispatch(&connections, stamp, &results);
+        }
+
+        let mut stamps = Vec::new();
+        while let Some(Some(ServerMsg::Frame { stamp, .. })) = queue.pop().now_or_never() {
+            stamps.push((stamp.since_tick, stamp.tick, stamp.seq));
+        }
+        assert_eq!(stamps, [(0, 5, 1), (5, 9, 2)]);
+    }
+
+    #[test]
+    fn reports_connections_and_queued_bytes() {
+        let mut world = World::new();
+        world.register::<Location>();
+        world.spawn(Location { city: "NYC".into() });
+        let connections = Connections::new(4, OverflowPolicy::default());
+        let (a, _a_queue, _) = connections.open(ConnectOptions::default());
+        let (b, _b_queue, _) = connections.open(ConnectOptions::default());
+
+        let api = EcsSubApi::default();
+        let query = |id: &str| QuerySubReq {
+            id: id.into(),
+            fetch: vec!["Location".into()],
+            ..default()
+        };
+        api.subscribe_components_for(Some(a), query("query_1"), &world)
+            .unwrap();
+        api.subscribe_components_for(None, query("app"), &world)
+            .unwrap();
+        let mut results = Vec::new();
+        api.write_all_queries(&world, |sub, resp| {
+            results.push(Routed {
+                connection: sub.connection,
+                stats: Some((sub.stats.clone(), resp.json.len())),
+                msg: ServerMsg::QuerySubResp(resp),
+            })
+        });
+        let len = |id: &str| {
+            results
+                .iter()
+                .find_map(|result| match &result.msg {
+                    ServerMsg::QuerySubResp(resp) if resp.id == id => Some(resp.json.len()),
+                    _ => None,
+                })
+                .unwrap() as u64
+        };
+        let (owned_len, app_len) = (len("query_1"), len("app"));
+        dispatch(&connections, Stamp::default(), &results);
+
+        let report = admin_report(&api, &connections);
+        let connection = |id| {
+            report
+                .connections
+                .iter()
+                .find(|info| info.id == id)
+                .unwrap()
+        };
+        assert_eq!(connection(a).subscriptions, ["query_1"]);
+        assert!(connection(b).subscriptions.is_empty());
+        assert_eq!(connection(a).queue.depth, 2);
+        assert_eq!(connection(b).queue.depth, 1);
+
+        let stats = |id: &str| {
+            report
+                .subscriptions
+                .iter()
+                .find(|info| info.req.id == id)
+                .unwrap()
+                .stats
+        };
+        assert_eq!(stats("query_1").runs, 1);
+        assert_eq!(stats("query_1").bytes_queued, owned_len);
+        // Queued once for every connection
+        assert_eq!(stats("app").bytes_queued, 2 * app_len);
+    }
+}
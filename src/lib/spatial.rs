@@ -0,0 +1,36 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Area of interest tested against each match's translation
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Region {
+    Aabb { min: [f32; 3], max: [f32; 3] },
+    Sphere { center: [f32; 3], radius: f32 },
+}
+
+impl Region {
+    pub fn contains(&self, point: Vec3) -> bool {
+        match *self {
+            Region::Aabb { min, max } => {
+                point.cmpge(Vec3::from(min)).all() && point.cmple(Vec3::from(max)).all()
+            }
+            Region::Sphere { center, radius } => {
+                point.distance_squared(Vec3::from(center)) <= radius * radius
+            }
+        }
+    }
+
+    /// Prefers `GlobalTransform` so children are tested in world space, entities
+    /// without any transform are never inside a region
+    pub fn contains_entity(&self, world: &World, entity: Entity) -> bool {
+        world
+            .get::<GlobalTransform>(entity)
+            .map(|transform| transform.translation())
+            .or_else(|| {
+                world
+                    .get::<Transform>(entity)
+                    .map(|transform| transform.translation)
+            })
+            .map_or(false, |translation| self.contains(translation))
+    }
+}
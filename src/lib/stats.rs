@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{outbound::QueueMetrics, ConnectOptions, ConnectionId, QueryId, QuerySubReq};
+
+/// Counters shared between the main thread running a subscription and the task
+/// dispatching its responses
+#[derive(Debug, Default)]
+pub struct SubscriptionStats {
+    runs: AtomicU64,
+    matches: AtomicU64,
+    last_run_ms: AtomicU64,
+    run_time_us: AtomicU64,
+    run_time_total_us: AtomicU64,
+    serialize_time_us: AtomicU64,
+    bytes_queued: AtomicU64,
+}
+
+impl SubscriptionStats {
+    pub fn record_run(&self, matches: usize, timestamp_ms: u64, run_time: Duration) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.matches.store(matches as u64, Ordering::Relaxed);
+        self.last_run_ms.store(timestamp_ms, Ordering::Relaxed);
+        self.run_time_us
+            .store(run_time.as_micros() as u64, Ordering::Relaxed);
+        self.run_time_total_us
+            .fetch_add(run_time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_serialize(&self, serialize_time: Duration) {
+        self.serialize_time_us
+            .store(serialize_time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Counted when a response is queued on a connection, before it is written out
+    pub fn record_queued(&self, bytes: usize) {
+        self.bytes_queued.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> SubscriptionStatsSnapshot {
+        SubscriptionStatsSnapshot {
+            runs: self.runs.load(Ordering::Relaxed),
+            matches: self.matches.load(Ordering::Relaxed),
+            last_run_ms: self.last_run_ms.load(Ordering::Relaxed),
+            run_time_us: self.run_time_us.load(Ordering::Relaxed),
+            run_time_total_us: self.run_time_total_us.load(Ordering::Relaxed),
+            serialize_time_us: self.serialize_time_us.load(Ordering::Relaxed),
+            bytes_queued: self.bytes_queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionStatsSnapshot {
+    pub runs: u64,
+    /// Matches returned by the last run
+    pub matches: u64,
+    /// Wall-clock time of the last run in milliseconds since the unix epoch
+    pub last_run_ms: u64,
+    /// Time the last run spent on the main thread
+    pub run_time_us: u64,
+    /// Main thread time summed over every run
+    pub run_time_total_us: u64,
+    /// Time the last response took to serialize
+    pub serialize_time_us: u64,
+    /// Response json bytes queued across all responses and recipients. Responses dropped
+    /// from a full queue later on still count.
+    pub bytes_queued: u64,
+}
+
+/// What actually went over a connection's socket, after framing and compression
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn record_sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionInfo {
+    pub connection: Option<ConnectionId>,
+    pub req: QuerySubReq,
+    pub stats: SubscriptionStatsSnapshot,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub options: ConnectOptions,
+    pub queue: QueueMetrics,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub subscriptions: Vec<QueryId>,
+}
+
+/// Everything an operator needs to find out which client costs what
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdminReport {
+    pub connections: Vec<ConnectionInfo>,
+    pub subscriptions: Vec<SubscriptionInfo>,
+}
@@ -0,0 +1,282 @@
+use std::{io, net::SocketAddr, path::PathBuf, pin::Pin};
+
+use bevy::prelude::*;
+use bevy_tokio_tasks::TaskContext;
+use bytes::Bytes;
+use futures_util::{future, Sink, SinkExt, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_tungstenite::{
+    tungstenite::{self, error::ProtocolError, protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::{
+    server::{spawn_connection, Connections},
+    ConnectOptions,
+};
+
+/// One message of a client connection, whatever transport carries it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Frame::Text(text) => text.as_bytes(),
+            Frame::Binary(bytes) => bytes,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Frame::Text(text) => text.into_bytes(),
+            Frame::Binary(bytes) => bytes,
+        }
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(bytes) => Message::Binary(bytes),
+        }
+    }
+}
+
+/// Write half of a client connection, whatever it runs over
+pub type FrameSink = Pin<Box<dyn Sink<Frame, Error = io::Error> + Send>>;
+/// Read half of a client connection, whatever it runs over
+pub type FrameStream = Pin<Box<dyn Stream<Item = io::Result<Frame>> + Send>>;
+
+/// Control messages are answered by tungstenite itself and left out of the stream
+pub fn websocket<S>(stream: WebSocketStream<S>) -> (FrameSink, FrameStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (write, read) = stream.split();
+    let write = write
+        .sink_map_err(websocket_error)
+        .with(|frame: Frame| future::ready(Ok::<_, io::Error>(Message::from(frame))));
+    let read = read.filter_map(|msg| {
+        future::ready(match msg {
+            Ok(Message::Text(text)) => Some(Ok(Frame::Text(text))),
+            Ok(Message::Binary(bytes)) => Some(Ok(Frame::Binary(bytes))),
+            Ok(_) => None,
+            Err(e) => Some(Err(websocket_error(e))),
+        })
+    });
+    (Box::pin(write), Box::pin(read))
+}
+
+/// Closed connections come out as `ConnectionAborted` whichever way they were closed,
+/// oversized messages as `InvalidInput` like on length-prefixed transports
+fn websocket_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed
+        | tungstenite::Error::AlreadyClosed
+        | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+            io::Error::new(io::ErrorKind::ConnectionAborted, e)
+        }
+        tungstenite::Error::Capacity(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// Each message is sent as one frame behind a 4 byte big endian length. The payload is
+/// whatever the WebSocket message would carry: json, or compressed json if the
+/// connection asked for compression. Every frame read is `Frame::Binary`. Frames are
+/// limited to the size of the largest WebSocket message, larger ones fail with
+/// `InvalidInput`.
+pub fn length_delimited<S>(stream: S) -> (FrameSink, FrameStream)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let max_frame_length = WebSocketConfig::default()
+        .max_message_size
+        .unwrap_or(usize::MAX);
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_codec();
+    let (write, read) = Framed::new(stream, codec).split();
+    let write = write
+        .with(|frame: Frame| future::ready(Ok::<_, io::Error>(Bytes::from(frame.into_bytes()))));
+    let read = read.map(|frame: io::Result<_>| frame.map(|bytes| Frame::Binary(bytes.to_vec())));
+    (Box::pin(write), Box::pin(read))
+}
+
+/// Accepts length-prefixed connections on a plain TCP socket. There is no handshake to
+/// negotiate options over, these connections use the default `ConnectOptions`.
+pub async fn listen_tcp(
+    ctx: TaskContext,
+    connections: Connections,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("TCP listening on: {}", addr);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept a TCP connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("Failed to set nodelay for {}: {}", peer, e);
+        }
+        spawn_connection(
+            &ctx,
+            &connections,
+            length_delimited(stream),
+            ConnectOptions::default(),
+            &peer.to_string(),
+        );
+    }
+}
+
+/// Same as `listen_tcp` on a Unix domain socket, for tools running on the same machine
+#[cfg(unix)]
+pub async fn listen_unix(
+    ctx: TaskContext,
+    connections: Connections,
+    path: PathBuf,
+) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::net::UnixListener;
+
+    // A socket left behind by a previous run would make bind fail. One that still
+    // accepts connections belongs to a running server and is left alone.
+    if std::fs::metadata(&path).map_or(false, |metadata| metadata.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            ));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    info!("Unix socket listening on: {}", path.display());
+    let peer = path.display().to_string();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept a Unix socket connection: {}", e);
+                continue;
+            }
+        };
+        spawn_connection(
+            &ctx,
+            &connections,
+            length_delimited(stream),
+            ConnectOptions::default(),
+            &peer,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, time::Duration};
+
+    use bevy_tokio_tasks::{TokioTasksPlugin, TokioTasksRuntime};
+
+    use super::*;
+    use crate::{
+        protocol::{ClientMsg, ServerMsg},
+        server::ServerPlugin,
+        Compression, QuerySubReq, RegistryExt,
+    };
+
+    #[derive(Component, Reflect, serde::Serialize)]
+    #[reflect(Serialize)]
+    struct Location {
+        city: String,
+    }
+
+    #[test]
+    fn subscribes_over_length_delimited_duplex() {
+        let mut app = App::new();
+        app.add_plugin(TokioTasksPlugin::default())
+            .add_plugins(MinimalPlugins)
+            .add_plugin(ServerPlugin::default());
+        app.world.register::<Location>();
+        app.world.spawn(Location { city: "NYC".into() });
+        // Runs the startup systems
+        app.update();
+
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let connections = app.world.resource::<Connections>().clone();
+        app.world
+            .resource::<TokioTasksRuntime>()
+            .spawn_background_task(move |ctx| async move {
+                let stream = length_delimited(server);
+                spawn_connection(&ctx, &connections, stream, default(), "duplex");
+            });
+
+        let client_rt = tokio::runtime::Runtime::new().unwrap();
+        let (mut write, mut read) = length_delimited(client);
+        let (frames, received) = mpsc::channel();
+        client_rt.spawn(async move {
+            while let Some(Ok(frame)) = read.next().await {
+                if frames.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+        let subscribe = ClientMsg::Subscribe(QuerySubReq {
+            id: "Locations".into(),
+            fetch: vec!["Location".into()],
+            ..default()
+        });
+        let subscribe = Frame::Text(serde_json::to_string(&subscribe).unwrap());
+        client_rt.block_on(write.send(subscribe)).unwrap();
+
+        // Requests are handled on the main thread, which only runs during updates. The ack
+        // and the first response are queued by different tasks, either may come first.
+        let (mut ack, mut resp) = (None, None);
+        while ack.is_none() || resp.is_none() {
+            app.update();
+            let Ok(frame) = received.recv_timeout(Duration::from_millis(10)) else {
+                continue;
+            };
+            // Everything read off a length-prefixed stream is binary
+            assert!(matches!(frame, Frame::Binary(_)));
+            match ServerMsg::from_frame(frame, Compression::None).unwrap() {
+                ServerMsg::Ack { req, .. } => ack = Some(req),
+                ServerMsg::QuerySubResp(query) => resp = Some(query),
+                msg => panic!("unexpected {msg:?}"),
+            }
+        }
+        let (ack, resp) = (ack.unwrap(), resp.unwrap());
+        assert_eq!(
+            (ack.id.as_str(), resp.id.as_str()),
+            ("Locations", "Locations")
+        );
+        let resp = resp.parse().unwrap();
+        assert!(resp.snapshot);
+        assert_eq!(resp.matches.len(), 1);
+    }
+
+    #[test]
+    fn rejects_frames_larger_than_a_websocket_message() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (server, _client) = tokio::io::duplex(1024);
+        let (mut write, _read) = length_delimited(server);
+        let max = WebSocketConfig::default().max_message_size.unwrap();
+        let e = rt
+            .block_on(write.send(Frame::Binary(vec![0; max + 1])))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
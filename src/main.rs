@@ -1,25 +1,19 @@
-use std::{error::Error, sync::Arc, time::Duration};
+use std::{error::Error, net::SocketAddr, path::PathBuf, time::Duration};
 
 use bevy::{app::ScheduleRunnerSettings, prelude::*};
-use bevy_tokio_tasks::{TaskContext, TokioTasksPlugin, TokioTasksRuntime};
+use bevy_tokio_tasks::{TokioTasksPlugin, TokioTasksRuntime};
 use clap::Parser;
 use crossbeam_channel::{Receiver, Sender};
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
-use json_ecs_sub::*;
-use serde::{Deserialize, Serialize};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
-};
-use tokio_tungstenite::{
-    tungstenite::{error::ProtocolError, Message},
-    WebSocketStream,
+use json_ecs_sub::{
+    lifecycle::{ClientDisconnected, SubscriptionAdded, SubscriptionRemoved},
+    outbound::OverflowPolicy,
+    server::{self, Connections, ServerPlugin},
+    transport, *,
 };
+use tokio::net::TcpListener;
 
 pub mod client;
+pub mod http;
 
 type Result<T = (), E = Box<dyn Error>> = core::result::Result<T, E>;
 
@@ -29,25 +23,62 @@ struct Args {
     /// It just works!
     #[clap(long, action)]
     pub is_server: bool,
+
+    /// Compression to request for server frames when running as a client: none, zstd or lz4
+    #[clap(long, default_value = "none", value_parser = parse_compression)]
+    pub compression: Compression,
+
+    /// Ask the server to bundle each frame's results into one message when running as a client
+    #[clap(long, action)]
+    pub batch: bool,
+
+    /// Messages buffered per connection before the overflow policy kicks in
+    #[clap(long, default_value_t = 64, value_parser = parse_queue_capacity)]
+    pub queue_capacity: usize,
+
+    /// What to do when a slow client's queue is full: drop-oldest, coalesce or disconnect
+    #[clap(long, default_value = "drop-oldest", value_parser = parse_overflow_policy)]
+    pub overflow_policy: OverflowPolicy,
+
+    /// Address of the HTTP server exposing `/metrics` and the REST API
+    #[clap(long, default_value = "127.0.0.1:3013")]
+    pub http_addr: SocketAddr,
+
+    /// Also accept length-prefixed connections on this TCP address. They have no handshake
+    /// to pick options in, so they use the defaults: no compression and no batching.
+    #[clap(long)]
+    pub tcp_addr: Option<SocketAddr>,
+
+    /// Also accept length-prefixed connections on this Unix domain socket, with the same
+    /// default options as --tcp-addr
+    #[clap(long)]
+    pub unix_socket: Option<PathBuf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum ClientMsg {
-    Subscribe(QuerySubReq),
-    Unsubscribe(QueryId),
+fn parse_compression(s: &str) -> Result<Compression, String> {
+    Compression::from_name(s).ok_or_else(|| format!("unknown compression '{s}'"))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum ServerMsg {
-    Ack(QuerySubReq),
-    QuerySubResp(QuerySubResp),
-    Text(String),
+fn parse_queue_capacity(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("queue capacity must be at least 1".to_string()),
+        Ok(capacity) => Ok(capacity),
+        Err(e) => Err(format!("invalid queue capacity '{s}': {e}")),
+    }
+}
+
+fn parse_overflow_policy(s: &str) -> Result<OverflowPolicy, String> {
+    OverflowPolicy::from_name(s).ok_or_else(|| format!("unknown overflow policy '{s}'"))
 }
 
 fn main() -> Result {
     let args = Args::parse();
     if !args.is_server {
-        client::client().unwrap();
+        client::client(ConnectOptions {
+            compression: args.compression,
+            batch: args.batch,
+        })
+        .unwrap();
     } else {
         server(args);
     }
@@ -55,7 +86,12 @@ fn main() -> Result {
 }
 
 fn server(args: Args) {
-    App::new()
+    let server = ServerPlugin {
+        queue_capacity: args.queue_capacity,
+        overflow_policy: args.overflow_policy,
+    };
+    let mut app = App::new();
+    app
         // .insert_resource(ScheduleRunnerSettings::run_once())
         .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
             1.0,
@@ -64,10 +100,12 @@ fn server(args: Args) {
         .add_plugin(TokioTasksPlugin::default())
         .add_plugins(MinimalPlugins)
         .add_plugin(bevy::log::LogPlugin::default())
+        .add_plugin(server)
         .add_startup_system(setup)
-        .add_system(query_runner)
-        .add_system(spawner)
-        .run();
+        .add_system(report_lifecycle)
+        .add_system(spawner);
+    app.register_event::<Damage>();
+    app.run();
 }
 
 #[derive(Debug, Component, Reflect, serde::Serialize)]
@@ -82,6 +120,12 @@ struct Health {
     pub health: u32,
 }
 
+#[derive(Debug, Reflect, serde::Serialize, serde::Deserialize)]
+#[reflect(Serialize, Deserialize)]
+struct Damage {
+    pub amount: u32,
+}
+
 #[derive(Resource)]
 struct DynamicQueryRequests(pub Receiver<QuerySubReq>);
 
@@ -89,26 +133,41 @@ struct DynamicQueryRequests(pub Receiver<QuerySubReq>);
 struct DynamicQueryResponse(pub Sender<QuerySubResp>);
 
 fn setup(world: &mut World) {
-    // let args = world.get_resource::<Args>().unwrap();
-    let writes = SubscriptionWsWrites::default();
-    world.insert_resource(writes.clone());
-    world.insert_resource(EcsSubApi::default());
     world.register::<Health>();
     world.register::<Location>();
-    let args = world.get_resource::<Args>().unwrap().clone();
-    let rt = world.get_resource::<TokioTasksRuntime>().unwrap();
-    rt.spawn_background_task(move |ctx| async move {
-        println!("This print executes from a background Tokio runtime thread");
-        if args.is_server {
-            println!("Server");
-            network(ctx, args, writes.clone()).await.unwrap();
-        } else {
-            println!("Client");
+    let args = world.resource::<Args>().clone();
+    let connections = world.resource::<Connections>().clone();
+    let rt = world.resource::<TokioTasksRuntime>();
+    rt.spawn_background_task({
+        let (addr, connections) = (args.http_addr, connections.clone());
+        move |ctx| async move {
+            http::serve(ctx, addr, connections).await.unwrap();
         }
     });
+    if let Some(addr) = args.tcp_addr {
+        let connections = connections.clone();
+        rt.spawn_background_task(move |ctx| async move {
+            transport::listen_tcp(ctx, connections, addr).await.unwrap();
+        });
+    }
+    #[cfg(unix)]
+    if let Some(path) = args.unix_socket.clone() {
+        let connections = connections.clone();
+        rt.spawn_background_task(move |ctx| async move {
+            transport::listen_unix(ctx, connections, path)
+                .await
+                .unwrap();
+        });
+    }
+    rt.spawn_background_task(move |ctx| async move {
+        let addr = "127.0.0.1:3012";
+        let listener = TcpListener::bind(addr).await.expect("Failed to bind");
+        info!("Listening on: {}", addr);
+        server::network(ctx, connections, listener).await;
+    });
 }
 
-fn spawner(mut commands: Commands, mut i: Local<u64>) {
+fn spawner(mut commands: Commands, mut damage: EventWriter<Damage>, mut i: Local<u64>) {
     match &*i {
         0 => {
             commands.spawn((
@@ -129,107 +188,26 @@ fn spawner(mut commands: Commands, mut i: Local<u64>) {
         }
         _ => {}
     };
+    damage.send(Damage { amount: *i as u32 });
     *i += 1;
 }
 
-fn query_runner(world: &mut World) {
-    let api = world.get_resource::<EcsSubApi>().unwrap();
-    let results = api.run_all_queries(world);
-
-    for resp in results.iter() {
-        println!("{}", serde_json::to_string_pretty(&resp).unwrap());
+fn report_lifecycle(
+    mut added: EventReader<SubscriptionAdded>,
+    mut removed: EventReader<SubscriptionRemoved>,
+    mut disconnected: EventReader<ClientDisconnected>,
+) {
+    for event in added.iter() {
+        info!(
+            "Connection {:?} subscribed to {}",
+            event.connection,
+            event.req.id()
+        );
     }
-
-    let Some(writes) = world.get_resource::<SubscriptionWsWrites>() else {
-        debug!("SubscriptionWsWrites res not yet inserted");
-        return;
-    };
-    let writes = writes.0.clone();
-
-    let rt = world.get_resource::<TokioTasksRuntime>().unwrap();
-    rt.spawn_background_task(move |_ctx| async move {
-        let mut writes = writes.lock().await;
-
-        let mut to_remove = Vec::new();
-        for (i, writer) in writes.iter_mut().enumerate() {
-            for result in &results {
-                let msg = Message::text(
-                    serde_json::to_string(&ServerMsg::QuerySubResp(result.clone())).unwrap(),
-                );
-                if let Err(e) = writer.send(msg.clone()).await {
-                    match e {
-                        tokio_tungstenite::tungstenite::Error::ConnectionClosed
-                        | tokio_tungstenite::tungstenite::Error::Protocol(
-                            ProtocolError::ResetWithoutClosingHandshake,
-                        )
-                        | tokio_tungstenite::tungstenite::Error::AlreadyClosed => {
-                            error!("Caught: {}", e);
-                            to_remove.push(i);
-                            continue;
-                        }
-                        _ => error!("Uncaught {}", e),
-                    }
-                };
-            }
-        }
-        for i in to_remove.iter().rev() {
-            let _ = writes.remove(*i);
-        }
-    });
-}
-
-#[derive(Clone, Resource, Default)]
-struct SubscriptionWsWrites(pub Arc<Mutex<Vec<SplitSink<WebSocketStream<TcpStream>, Message>>>>);
-
-async fn network(
-    ctx: TaskContext,
-    _args: Args,
-    subscription_ws_writes: SubscriptionWsWrites,
-) -> Result {
-    let addr = "127.0.0.1:3012".to_string();
-
-    // Create the event loop and TCP listener we'll accept connections on.
-    let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
-    info!("Listening on: {}", addr);
-
-    while let Ok((stream, _)) = listener.accept().await {
-        let addr = stream
-            .peer_addr()
-            .expect("connected streams should have a peer address");
-        info!("Peer address: {}", addr);
-
-        let ws_stream = tokio_tungstenite::accept_async(stream)
-            .await
-            .expect("Error during the websocket handshake occurred");
-        let (write, read) = ws_stream.split();
-
-        subscription_ws_writes.0.lock().await.push(write);
-
-        info!("New WebSocket connection: {}", addr);
-        tokio::spawn(incoming(ctx.clone(), read));
+    for event in removed.iter() {
+        info!("Connection {:?} dropped {}", event.connection, event.id);
     }
-
-    Ok(())
-}
-
-async fn incoming(mut ctx: TaskContext, mut read: SplitStream<WebSocketStream<TcpStream>>) {
-    while let Some(msg) = read.next().await {
-        let Ok(msg) = msg else {
-            error!("{:?}", msg);
-            continue;
-        };
-        let Message::Text(msg) = msg else {
-                            println!("Expected only test messages");
-                            continue;
-                        };
-        let query: QuerySubReq = serde_json::from_str(&msg).unwrap();
-        println!("Req: {query:?}");
-        ctx.run_on_main_thread(|ctx| {
-            let api = ctx.world.remove_resource::<EcsSubApi>().unwrap_or_default();
-            api.subscribe_components(query, ctx.world);
-            ctx.world.insert_resource(api);
-        })
-        .await;
-        println!("Inserted subscription request into EcsSubApi within world");
+    for event in disconnected.iter() {
+        info!("Connection {} disconnected", event.connection);
     }
 }